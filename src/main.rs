@@ -1,456 +1,9109 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
 use std::fs::File;
-use std::io::BufReader;
-use std::sync::Arc;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
 use yang2::context::{Context, ContextFlags};
+use yang2::data::{DataFormat, DataParserFlags, DataTree, DataValidationFlags};
 use yang2::schema::DataValueType;
+use yang2::schema::SchemaModule;
 use yang2::schema::SchemaNode;
 use yang2::schema::SchemaNodeKind;
+use yang2::schema::SchemaPathFormat;
+
+/// Version of the yang2 bindings, which cargo only exposes for the crate
+/// itself. Keep in sync with Cargo.toml.
+const YANG2_VERSION: &str = "0.8";
 
 enum Mode {
+    Version,
     NixOptions,
-    Convert(ConvertMode, File),
-    Diff(File, File),
+    Probe,
+    /// Without a direction, it is detected from the input.
+    Convert(Option<ConvertMode>, PathBuf),
+    Diff(PathBuf, PathBuf),
+    DiffN(Vec<PathBuf>),
+    Grep(String, Option<PathBuf>),
+    /// Apply an RFC 8072 YANG Patch document to a data tree.
+    PatchApply {
+        base: PathBuf,
+        patch: PathBuf,
+    },
+    /// Round-trip the defaults of the schema through both conversions.
+    SelfTest,
+    /// Check that a committed options file is what nix_options generates.
+    Verify {
+        reference: PathBuf,
+    },
+}
+
+/// What to print, for the modes that can print more than one thing.
+#[derive(PartialEq)]
+enum Emit {
+    Json,
+    Nix,
+    /// A NixOS VM test evaluating the generated options.
+    NixosTest,
+    /// Terraform variables mirroring the schema.
+    Terraform,
+}
+
+enum DiffFormat {
+    Text,
+    /// RFC 8072 YANG Patch document turning the first tree into the second.
+    YangPatch,
+    /// The yang2nix form of what changed, to deep-merge into the first tree,
+    /// and the attribute paths to remove from it.
+    NixOverlay,
+    /// The operations of the text format as JSON objects.
+    Json,
 }
 
+#[derive(PartialEq, Clone, Debug)]
 enum ConvertMode {
     Nix2Yang,
     Yang2Nix,
 }
 
-fn print_nix_options(indent: &mut String, root: SchemaNode) {
-    let mut stack = vec![root];
+/// A document in the YANG representation: RFC 7951 JSON with lists as arrays
+/// of entries.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+struct YangDoc(serde_json::Value);
 
-    while let Some(node) = stack.pop() {
-        //println!("{}}}", indent);
-        match node.kind() {
-            SchemaNodeKind::Container => {
-                if let Some(description) = node.description() {
-                    println!("\n{}# {}", indent, description);
-                }
-                println!("{}{} = {{", indent, node.name());
-                *indent += "  ";
-                for child in node.children() {
-                    print_nix_options(indent, child);
-                }
-                *indent = indent.chars().skip(2).collect();
-                println!("{}}};", indent);
-            }
+/// A document in the Nix representation: keyed lists as attrsets nested once
+/// per key, with Nix-style member names.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+struct NixDoc(serde_json::Value);
 
-            SchemaNodeKind::List => {
-                println!("\n{}{} = lib.mkOption {{", indent, node.name());
-                *indent += "  ";
+impl YangDoc {
+    fn into_inner(self) -> serde_json::Value {
+        self.0
+    }
+}
 
-                println!("{}description = ''", indent);
-                if let Some(description) = node.description() {
-                    println!("{}  {}", indent, description);
-                }
-                for (i, key) in node.list_keys().enumerate() {
-                    println!("{}  Key {}: {}", indent, i + 1, key.name());
-                }
-                println!("{}'';", indent);
+impl NixDoc {
+    fn into_inner(self) -> serde_json::Value {
+        self.0
+    }
+}
 
-                print!("{}type = ", indent);
-                for key in node.list_keys() {
-                    print!("lib.types.attrsOf (");
-                }
-                println!("lib.types.submodule {{\n");
-                *indent += "  ";
-                println!("{}options = {{", indent);
-                *indent += "  ";
+/// A document in either representation. It serializes as the document
+/// itself, but can only be told apart with the schema, see `Doc::detect`.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum Doc {
+    Yang(YangDoc),
+    Nix(NixDoc),
+}
 
-                for child in node.children() {
-                    if !child.is_list_key() {
-                        print_nix_options(indent, child);
-                    }
-                }
+impl Doc {
+    /// A document in the representation `mode` converts from.
+    fn new(value: serde_json::Value, mode: &ConvertMode) -> Doc {
+        match mode {
+            ConvertMode::Yang2Nix => Doc::Yang(YangDoc(value)),
+            ConvertMode::Nix2Yang => Doc::Nix(NixDoc(value)),
+        }
+    }
 
-                *indent = indent.chars().skip(2).collect();
-                println!("\n{}}};", indent);
-                *indent = indent.chars().skip(2).collect();
-                print!("\n{}}}", indent);
-                for key in node.list_keys() {
-                    print!(")");
-                }
-                println!(";");
-                println!("\n{}default = {{}};", indent);
-                *indent = indent.chars().skip(2).collect();
-                println!("{}}};", indent);
-            }
+    /// The direction the document converts in.
+    fn mode(&self) -> ConvertMode {
+        match self {
+            Doc::Yang(_) => ConvertMode::Yang2Nix,
+            Doc::Nix(_) => ConvertMode::Nix2Yang,
+        }
+    }
 
-            SchemaNodeKind::Choice => {
-                println!("\n{}{} = {{", indent, node.name());
-                *indent += "  ";
-                for child in node.children() {
-                    print_nix_options(indent, child);
-                }
-                *indent = indent.chars().skip(2).collect();
-                println!("{}}};", indent);
-            }
+    /// Convert the document to the other representation.
+    fn convert(
+        self,
+        ctx: &Arc<Context>,
+        path: &Path,
+        options: &ConvertOptions,
+        report: &mut Report,
+    ) -> Result<Doc, Vec<ConvertError>> {
+        match self {
+            Doc::Yang(doc) => yang_to_nix(ctx, path, doc, options, report).map(Doc::Nix),
+            Doc::Nix(doc) => nix_to_yang(ctx, path, doc, options, report).map(Doc::Yang),
+        }
+    }
 
-            SchemaNodeKind::Case => {
-                println!("\n{}{} = {{", indent, node.name());
-                *indent += "  ";
-                for child in node.children() {
-                    print_nix_options(indent, child);
-                }
-                *indent = indent.chars().skip(2).collect();
-                println!("{}}};", indent);
-            }
+    /// The document of a representation that the caller doesn't care about,
+    /// e.g. to print it.
+    fn into_inner(self) -> serde_json::Value {
+        match self {
+            Doc::Yang(doc) => doc.into_inner(),
+            Doc::Nix(doc) => doc.into_inner(),
+        }
+    }
 
-            SchemaNodeKind::Leaf | SchemaNodeKind::LeafList => {
-                println!("\n{}{} = lib.mkOption {{", indent, node.name());
-                if let Some(description) = node.description() {
-                    println!("{}  description = \"{}\";", indent, description);
-                };
-                let leaf_type = match node.base_type() {
-                    Some(DataValueType::Enum) => "lib.types.str",
-                    Some(DataValueType::Union) => "lib.types.str",
-                    Some(DataValueType::String) => "lib.types.str",
-                    Some(DataValueType::Int8) => "lib.types.ints.s8",
-                    Some(DataValueType::Uint8) => "lib.types.ints.u8",
-                    Some(DataValueType::Uint16) => "lib.types.ints.u16",
-                    Some(DataValueType::Uint32) => "lib.types.ints.u32",
-                    Some(DataValueType::Uint64) => "lib.types.ints.unsigned",
-                    Some(DataValueType::Dec64) => "lib.types.number",
-                    other => todo!("{:?}", other),
-                };
-                match node.kind() {
-                    SchemaNodeKind::Leaf if !node.is_mandatory() => {
-                        println!("{}  type = lib.types.nullOr {};", indent, leaf_type)
-                    }
-                    SchemaNodeKind::Leaf => println!("{}  type = {};", indent, leaf_type),
-                    SchemaNodeKind::LeafList => {
-                        println!("{}  type = lib.types.listOf {};", indent, leaf_type)
-                    }
-                    _ => unreachable!(),
-                }
-                match node.kind() {
-                    SchemaNodeKind::Leaf if !node.is_mandatory() => {
-                        println!("{}  default = null;", indent)
-                    }
-                    SchemaNodeKind::LeafList => println!("{}  default = [];", indent),
-                    _ => {}
+    /// Tell the representation of a document by its first keyed list: YANG
+    /// documents hold arrays of entries, Nix documents attrsets. Documents
+    /// without keyed lists, which both conversions leave alone, are given
+    /// back.
+    fn detect(
+        value: serde_json::Value,
+        top_level: &[SchemaNode],
+    ) -> Result<Doc, serde_json::Value> {
+        match keyed_list_is_array(&value, top_level, true) {
+            Some(true) => Ok(Doc::Yang(YangDoc(value))),
+            Some(false) => Ok(Doc::Nix(NixDoc(value))),
+            None => Err(value),
+        }
+    }
+}
+
+/// Whether the first keyed list in an object is an array of entries, if
+/// there is one. At the top level, members that aren't schema nodes are
+/// looked into as envelopes.
+fn keyed_list_is_array(
+    value: &serde_json::Value,
+    children: &[SchemaNode],
+    top_level: bool,
+) -> Option<bool> {
+    for (key, member) in value.as_object()? {
+        let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+        let Some(node) = children.iter().find(|child| child.name() == name) else {
+            if top_level {
+                if let found @ Some(_) = keyed_list_is_array(member, children, false) {
+                    return found;
                 }
-                println!("{}}};", indent);
             }
-            other => todo!("{:?}", other),
+            continue;
+        };
+        let found = match node.kind() {
+            SchemaNodeKind::List if !node.is_keyless_list() => match member {
+                serde_json::Value::Array(_) => Some(true),
+                serde_json::Value::Object(_) => Some(false),
+                _ => None,
+            },
+            SchemaNodeKind::List => {
+                let children = data_children(node.children());
+                member
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find_map(|entry| keyed_list_is_array(entry, &children, false))
+            }
+            SchemaNodeKind::Container => {
+                keyed_list_is_array(member, &data_children(node.children()), false)
+            }
+            _ => None,
+        };
+        if found.is_some() {
+            return found;
         }
     }
+    None
+}
+
+/// Identifier qualifying member names with their module in YANG-style
+/// documents.
+///
+/// RFC 7951 qualifies members with the name of the module defining them,
+/// which is also what libyang expects. Some tools use the module's shorter
+/// YANG `prefix` instead; with `Prefix` such documents are accepted by
+/// yang2nix and produced by nix2yang, while the module name is still used
+/// internally and for validation.
+#[derive(Default)]
+enum JsonNamespace {
+    #[default]
+    ModuleName,
+    Prefix,
+}
+
+#[derive(Default)]
+struct ConvertOptions {
+    rewrite_rules: Vec<(String, String)>,
+    auto_camelcase: bool,
+    /// Strip module prefixes from member names where the bare name is
+    /// unambiguous.
+    strip_prefixes: bool,
+    /// Coerce leaf values to their RFC 7951 encoding in nix2yang.
+    coerce: bool,
+    /// Only report errors, don't print the converted document.
+    check: bool,
+    /// Validate the YANG-style side of the conversion against the schema.
+    validate: bool,
+    json_namespace: JsonNamespace,
+    /// Leave the key leaves in the entries of keyed lists in yang2nix, in
+    /// addition to naming the entries.
+    keep_keys: bool,
+    /// Drop subtrees of modules that aren't loaded instead of failing.
+    skip_unknown_modules: bool,
+    /// Only convert the lists at or below this data path.
+    schema_path: Option<String>,
+    /// How to handle list entries with the same keys in yang2nix. Without
+    /// it, later entries silently replace earlier ones.
+    deduplicate: Option<Deduplicate>,
+    /// Only keep nodes with these RFC 8342 origins in yang2nix.
+    origins: Vec<String>,
+    /// Only convert the subtree at this JSON pointer.
+    json_pointer: Option<String>,
+    /// Fail on top-level members that belong to no loaded module, rather
+    /// than leaving them alone.
+    reject_unknown: bool,
+    /// Envelope members to convert the contents of, in addition to
+    /// `ietf-restconf:data`.
+    unwrap: Vec<String>,
+    /// Leave the converted contents of an envelope unwrapped.
+    no_rewrap: bool,
+    /// Escape non-ASCII characters in the output.
+    ascii: bool,
+    invalid_chars: InvalidChars,
+    /// Only convert these top-level members, dropping all others.
+    only: Vec<String>,
+    /// Check the JSON types of leaf values in nix2yang before converting.
+    type_assertion: bool,
+    /// Report type errors found by `type_assertion` as warnings only.
+    ignore_type_errors: bool,
+    /// Key names by list data path, for Nix-style files nesting the keys of
+    /// lists in other than schema order.
+    key_orders: BTreeMap<String, Vec<String>>,
+    /// Convert each of a sequence of JSON documents in the input, printing
+    /// one converted document per line.
+    ndjson: bool,
+    /// Member to add to the entries of keyed lists in yang2nix, holding the
+    /// data path of the list.
+    schema_path_field: Option<String>,
+    /// Warn about lists with more entries than this in yang2nix.
+    max_list_size: Option<usize>,
+    /// Fail on lists with more entries than this.
+    max_entries_per_list: Option<usize>,
+    /// Fail on documents with more JSON values than this.
+    max_total_nodes: Option<usize>,
+    normalize_keys: NormalizeKeys,
+    /// The Nix-style side nests the top-level members of each module under
+    /// the module's name, as `nix_options --namespace-by-module` generates.
+    namespace_by_module: bool,
+    /// Prefix of the top-level attribute names of the Nix-style side, e.g.
+    /// to keep the configs of several devices apart when merging them.
+    key_prefix: Option<String>,
+    /// Only compare, and validate, the data below these paths.
+    visible_paths: Option<VisiblePaths>,
+    /// Remove empty containers and lists and null leaves from the output of
+    /// yang2nix.
+    prune_empty: bool,
+    parse: ParseOptions,
 }
 
-fn set_color(op: yang2::data::DataDiffOp) {
-    match op {
-        yang2::data::DataDiffOp::Create => {
-            print!("\x1b[92m+ ");
+/// How libyang parses data trees. Unset options take the defaults of the
+/// mode: validating a document is strict, while diff and the other modes
+/// reading data trees are lenient and don't validate.
+#[derive(Default)]
+struct ParseOptions {
+    /// Fail on data without a schema node instead of ignoring it.
+    strict: Option<bool>,
+    /// Validate the parsed tree, e.g. for missing mandatory nodes.
+    validate: Option<bool>,
+    /// Only validate the modules the data has nodes of, for partial configs.
+    present_only: bool,
+}
+
+impl ParseOptions {
+    fn flags(&self, strict: bool, validate: bool) -> (DataParserFlags, DataValidationFlags) {
+        let mut parser_flags = DataParserFlags::empty();
+        if self.strict.unwrap_or(strict) {
+            parser_flags |= DataParserFlags::STRICT;
         }
-        yang2::data::DataDiffOp::Delete => {
-            print!("\x1b[91m- ");
+        if !self.validate.unwrap_or(validate) {
+            parser_flags |= DataParserFlags::NO_VALIDATION;
         }
-        yang2::data::DataDiffOp::Replace => {
-            print!("\x1b[93m~ ");
+        let mut validation_flags = DataValidationFlags::empty();
+        if self.present_only {
+            validation_flags |= DataValidationFlags::PRESENT;
         }
+        (parser_flags, validation_flags)
     }
 }
 
-fn reset_color() {
-    print!("\x1b[0m");
+/// What to do with string values containing characters that YANG strings
+/// can't hold, i.e. control characters other than tab, line feed and
+/// carriage return, and the noncharacters U+FFFE and U+FFFF.
+#[derive(Default)]
+enum InvalidChars {
+    #[default]
+    Error,
+    /// Remove them with a warning.
+    Strip,
 }
 
-fn main() -> std::io::Result<()> {
-    let mut args = std::env::args();
+enum Deduplicate {
+    /// Warn and keep the last entry.
+    Last,
+    /// Fail the conversion.
+    Error,
+}
 
-    drop(args.next());
+/// How to normalize the string key values of list entries.
+#[derive(Default, Clone, Copy, PartialEq)]
+enum NormalizeKeys {
+    #[default]
+    None,
+    /// Remove leading and trailing whitespace.
+    Trim,
+    Lowercase,
+}
 
-    let mode = match args.next().as_ref().map(|x| x.as_str()) {
-        Some("yang2nix") => Mode::Convert(
-            ConvertMode::Yang2Nix,
-            std::fs::File::open(&args.next().expect("filename")).expect("realpath"),
-        ),
-        Some("nix2yang") => Mode::Convert(
-            ConvertMode::Nix2Yang,
-            std::fs::File::open(&args.next().expect("filename")).expect("realpath"),
-        ),
-        Some("nix_options") => Mode::NixOptions,
-        Some("diff") => Mode::Diff(
-            std::fs::File::open(&args.next().expect("filename")).expect("realpath"),
-            std::fs::File::open(&args.next().expect("filename")).expect("realpath"),
-        ),
-        _ => panic!("mode: yang2nix nix2yang"),
-    };
+impl NormalizeKeys {
+    fn apply(self, key: &str) -> String {
+        match self {
+            NormalizeKeys::None => key.to_string(),
+            NormalizeKeys::Trim => key.trim().to_string(),
+            NormalizeKeys::Lowercase => key.to_lowercase(),
+        }
+    }
+}
 
-    std::env::set_current_dir(std::env::var("YANG_SCHEMAS_DIR").expect("env var YANG_SCHEMAS_DIR"))
-        .expect("Failed to set YANG search directory");
-    // Initialize context.
-    let mut ctx = Context::new(ContextFlags::NO_YANGLIBRARY).expect("Failed to create context");
+impl ConvertOptions {
+    /// Keys of a list in the order of the attribute set levels its entries
+    /// are nested in: level i holds the values of key i, in schema key order
+    /// unless overridden with `--key-order`.
+    fn key_order<'a>(&self, node: &SchemaNode<'a>) -> Result<Vec<SchemaNode<'a>>, String> {
+        let keys = node.list_keys().collect::<Vec<_>>();
+        let Some(order) = self.key_orders.get(&node.path(SchemaPathFormat::DATA)) else {
+            return Ok(keys);
+        };
+        let ordered = order
+            .iter()
+            .filter_map(|name| keys.iter().find(|key| key.name() == name).cloned())
+            .collect::<Vec<_>>();
+        if ordered.len() != keys.len() || order.len() != keys.len() {
+            let names = keys.iter().map(|key| key.name()).collect::<Vec<_>>();
+            return Err(format!(
+                "--key-order {} is not an order of the keys {}",
+                order.join(","),
+                names.join(",")
+            ));
+        }
+        Ok(ordered)
+    }
 
-    ctx.load_module("rtbrick-config", None, &[])
-        .expect("Failed to load module");
+    /// Name of a schema node as it appears in Nix-style data.
+    fn nix_name(&self, yang_name: &str) -> String {
+        if let Some((_, to)) = self
+            .rewrite_rules
+            .iter()
+            .find(|(from, _)| from == yang_name)
+        {
+            return to.clone();
+        }
+        if self.auto_camelcase {
+            let mut name = String::new();
+            let mut upper = false;
+            for c in yang_name.chars() {
+                if c == '-' {
+                    upper = true;
+                } else if upper {
+                    name.extend(c.to_uppercase());
+                    upper = false;
+                } else {
+                    name.push(c);
+                }
+            }
+            return name;
+        }
+        yang_name.to_string()
+    }
 
-    //for module in ctx.modules(false) {
-    //    eprintln!("loaded module {}@{:?}", module.name(), module.revision());
-    //}
+    fn renames(&self) -> bool {
+        self.auto_camelcase || !self.rewrite_rules.is_empty() || self.strip_prefixes
+    }
+}
 
-    let ctx = Arc::new(ctx);
+/// Data children of a schema node, with choice and case nodes flattened as they
+/// don't appear in data.
+fn data_children<'a>(children: impl Iterator<Item = SchemaNode<'a>>) -> Vec<SchemaNode<'a>> {
+    children
+        .flat_map(|child| match child.kind() {
+            SchemaNodeKind::Choice | SchemaNodeKind::Case => data_children(child.children()),
+            _ => vec![child],
+        })
+        .collect()
+}
 
-    let module = ctx.get_module_latest("rtbrick-config").unwrap();
+/// Rename the members of a Nix-style object between their YANG and Nix names.
+///
+/// Only members that correspond to schema nodes are renamed, so list keys (which
+/// are data) are left alone. Towards Nix, module prefixes are stripped if
+/// requested and the bare name is unambiguous; towards YANG, members are
+/// qualified as RFC 7951 requires, i.e. on module boundaries. Members that
+/// would be renamed to the same name are reported rather than merged.
+fn rename_members(
+    value: &mut serde_json::Value,
+    path: &str,
+    parent_module: Option<&str>,
+    children: &[SchemaNode],
+    options: &ConvertOptions,
+    to_nix: bool,
+    errors: &mut Vec<ConvertError>,
+) {
+    let object = match value {
+        serde_json::Value::Object(o) => o,
+        _ => return,
+    };
 
-    let roots = module.data();
+    let mut sources = BTreeMap::new();
+    for (key, mut member) in std::mem::take(object) {
+        let (prefix, name) = match key.split_once(':') {
+            Some((prefix, name)) => (Some(prefix), name),
+            None => (None, key.as_str()),
+        };
+        let candidates = children
+            .iter()
+            .filter(|child| {
+                if to_nix {
+                    child.name() == name
+                } else {
+                    options.nix_name(child.name()) == name
+                }
+            })
+            .collect::<Vec<_>>();
+        let child = candidates
+            .iter()
+            .find(|child| prefix.is_none_or(|prefix| child.module().name() == prefix));
 
-    let (mode, file) = match mode {
-        Mode::Convert(mode, file) => (mode, file),
-        Mode::NixOptions => {
-            println!("{{ lib, ... }}: {{");
-            let mut indent = "  ".to_string();
-            for root in roots {
-                print_nix_options(&mut indent, root);
+        let member_path = json_pointer_push(path, &key);
+        let new_key = match child {
+            Some(_) if prefix.is_none() && candidates.len() > 1 => {
+                eprintln!(
+                    "warning: {}: ambiguous member name, it needs a module prefix",
+                    member_path
+                );
+                key.clone()
             }
-            println!("}}");
-            std::process::exit(0);
+            Some(child) => {
+                rename_node(&mut member, &member_path, child, options, to_nix, errors);
+                let module = child.module().name().to_string();
+                if to_nix {
+                    let name = options.nix_name(child.name());
+                    match prefix {
+                        Some(_) if options.strip_prefixes && candidates.len() > 1 => {
+                            eprintln!(
+                                "warning: {}: keeping module prefix of ambiguous member",
+                                member_path
+                            );
+                            format!("{}:{}", module, name)
+                        }
+                        Some(_) if !options.strip_prefixes => format!("{}:{}", module, name),
+                        _ => name,
+                    }
+                } else if parent_module == Some(module.as_str()) {
+                    child.name().to_string()
+                } else {
+                    format!("{}:{}", module, child.name())
+                }
+            }
+            None => key.clone(),
+        };
+        if let Some(other) = sources.get(&new_key) {
+            errors.push(ConvertError::new(
+                &member_path,
+                format!(
+                    "members {} and {} are both renamed to {}",
+                    other, key, new_key
+                ),
+            ));
+            continue;
         }
-        Mode::Diff(file1, file2) => {
-            use yang2::data::{
-                Data, DataDiffFlags, DataFormat, DataParserFlags, DataPrinterFlags, DataTree,
-                DataValidationFlags,
-            };
-
-            // Parse data trees from JSON strings.
-            let dtree1 = DataTree::parse_file(
-                &ctx,
-                file1,
-                DataFormat::JSON,
-                DataParserFlags::NO_VALIDATION,
-                DataValidationFlags::empty(),
-            )
-            .expect("Failed to parse data tree");
+        sources.insert(new_key.clone(), key);
+        object.insert(new_key, member);
+    }
+}
 
-            let dtree2 = DataTree::parse_file(
-                &ctx,
-                file2,
-                DataFormat::JSON,
-                DataParserFlags::NO_VALIDATION,
-                DataValidationFlags::empty(),
-            )
-            .expect("Failed to parse data tree");
+/// The entries of a list in a Nix-style document, with their JSON pointers.
+///
+/// Keyed lists are descended through one attrset level per key, keyless lists
+/// are arrays in both representations.
+fn nix_list_entries<'v>(
+    value: &'v mut serde_json::Value,
+    path: &str,
+    node: &SchemaNode,
+) -> Vec<(String, &'v mut serde_json::Value)> {
+    if node.is_keyless_list() {
+        return value
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, x)| (json_pointer_push(path, &i.to_string()), x))
+            .collect();
+    }
 
-            // Compare data trees.
-            let diff = dtree1
-                .diff(&dtree2, DataDiffFlags::empty())
-                .expect("Failed to compare data trees");
+    let mut entries = vec![(path.to_string(), value)];
+    for _ in node.list_keys() {
+        entries = entries
+            .into_iter()
+            .flat_map(|(path, x)| {
+                x.as_object_mut()
+                    .into_iter()
+                    .flat_map(|o| o.iter_mut())
+                    .map(move |(k, x)| (json_pointer_push(&path, k), x))
+            })
+            .collect();
+    }
+    entries
+}
 
-            let dtree1_root = dtree1.reference();
-            let dtree2_root = dtree2.reference();
+fn rename_node(
+    value: &mut serde_json::Value,
+    path: &str,
+    node: &SchemaNode,
+    options: &ConvertOptions,
+    to_nix: bool,
+    errors: &mut Vec<ConvertError>,
+) {
+    let module = node.module().name().to_string();
+    let children = data_children(node.children());
+    match node.kind() {
+        SchemaNodeKind::Container => rename_members(
+            value,
+            path,
+            Some(&module),
+            &children,
+            options,
+            to_nix,
+            errors,
+        ),
+        SchemaNodeKind::List => {
+            for (path, entry) in nix_list_entries(value, path, node) {
+                rename_members(
+                    entry,
+                    &path,
+                    Some(&module),
+                    &children,
+                    options,
+                    to_nix,
+                    errors,
+                );
+            }
+        }
+        _ => {}
+    }
+}
 
-            for (op, dnode) in diff.iter() {
-                set_color(op);
-                println!("{:?} @{}", op, dnode.path());
-                let diffs_to_print = match op {
-                    yang2::data::DataDiffOp::Replace => vec![
-                        (
-                            yang2::data::DataDiffOp::Delete,
-                            dtree1_root
-                                .as_ref()
-                                .unwrap()
-                                .find_path(&dnode.path())
-                                .unwrap(),
-                        ),
-                        (
-                            yang2::data::DataDiffOp::Create,
-                            dtree2_root
-                                .as_ref()
-                                .unwrap()
-                                .find_path(&dnode.path())
-                                .unwrap(),
-                        ),
-                    ],
-                    yang2::data::DataDiffOp::Delete => vec![(
-                        op,
-                        dtree1_root
-                            .as_ref()
-                            .unwrap()
-                            .find_path(&dnode.path())
-                            .unwrap(),
-                    )],
-                    yang2::data::DataDiffOp::Create => vec![(
-                        op,
-                        dtree2_root
-                            .as_ref()
-                            .unwrap()
-                            .find_path(&dnode.path())
-                            .unwrap(),
-                    )],
-                };
+/// Coerce a leaf value to its RFC 7951 encoding, based on the leaf's type.
+///
+/// The bindings don't expose enum members, so booleans for enums are assumed
+/// to map to members literally named `true` and `false`.
+fn coerce_value(value: &mut serde_json::Value, node: &SchemaNode) -> Result<(), String> {
+    use serde_json::Value;
 
-                for (op, dnode) in diffs_to_print {
-                    let diff_str = dnode
-                        .print_string(DataFormat::JSON, DataPrinterFlags::empty())
-                        .expect("Failed to print data diff")
-                        .unwrap();
-                    for line in diff_str.lines() {
-                        set_color(op);
-                        println!("{}", line);
-                    }
-                }
-                println!();
-            }
-            reset_color();
-            std::process::exit(0);
+    let base_type = match node.base_type() {
+        Some(base_type) => base_type,
+        None => return Ok(()),
+    };
+    let coerced = match (base_type, &*value) {
+        (_, Value::Null) => return Ok(()),
+        (
+            DataValueType::Int8
+            | DataValueType::Int16
+            | DataValueType::Int32
+            | DataValueType::Uint8
+            | DataValueType::Uint16
+            | DataValueType::Uint32,
+            Value::Number(n),
+        ) if n.is_i64() || n.is_u64() => return Ok(()),
+        (
+            DataValueType::Int8
+            | DataValueType::Int16
+            | DataValueType::Int32
+            | DataValueType::Uint8
+            | DataValueType::Uint16
+            | DataValueType::Uint32,
+            Value::String(s),
+        ) => s.parse::<i64>().ok().map(Value::from),
+        (DataValueType::Int64, Value::Number(n)) if n.is_i64() => Some(Value::from(n.to_string())),
+        (DataValueType::Uint64, Value::Number(n)) if n.is_u64() => Some(Value::from(n.to_string())),
+        (DataValueType::Dec64, Value::Number(n)) => Some(Value::from(n.to_string())),
+        (DataValueType::Int64, Value::String(s)) if s.parse::<i64>().is_ok() => return Ok(()),
+        (DataValueType::Uint64, Value::String(s)) if s.parse::<u64>().is_ok() => return Ok(()),
+        (DataValueType::Dec64, Value::String(s)) if s.parse::<f64>().is_ok() => return Ok(()),
+        (DataValueType::Bool, Value::Bool(_)) => return Ok(()),
+        (DataValueType::Bool, Value::String(s)) => s.parse::<bool>().ok().map(Value::from),
+        (DataValueType::Empty, Value::Bool(true)) => Some(serde_json::json!([null])),
+        (DataValueType::Empty, Value::Array(_)) => return Ok(()),
+        (DataValueType::Enum, Value::Bool(b)) => Some(Value::from(b.to_string())),
+        (DataValueType::String | DataValueType::Enum, Value::Number(n)) => {
+            Some(Value::from(n.to_string()))
+        }
+        (
+            DataValueType::String
+            | DataValueType::Enum
+            | DataValueType::Binary
+            | DataValueType::Bits
+            | DataValueType::IdentityRef
+            | DataValueType::InstanceId,
+            Value::String(_),
+        ) => return Ok(()),
+        // the effective type isn't known up front
+        (DataValueType::Union | DataValueType::LeafRef | DataValueType::Unknown, _) => {
+            return Ok(())
         }
+        _ => None,
     };
 
-    let mut data: serde_json::Value = serde_json::from_reader(BufReader::new(file))?;
+    match coerced {
+        Some(coerced) => {
+            *value = coerced;
+            Ok(())
+        }
+        None => Err(format!("expected {:?}, found {}", base_type, value)),
+    }
+}
 
-    for node in roots
-        .flat_map(|root| root.traverse().collect::<Vec<_>>().into_iter().rev())
-        // only lists that have keys
-        .filter(|node| node.kind() == SchemaNodeKind::List && !node.is_keyless_list())
-    {
-        let key_names = node
-            .list_keys()
-            .map(|ch| format!("{}", ch.name()))
-            .collect::<Vec<_>>();
-        let mut p = vec![&mut data];
+/// Whether the leaf's values are encoded as strings in RFC 7951 because they
+/// may not fit a Nix integer or float.
+fn is_wide(node: &SchemaNode) -> bool {
+    matches!(
+        node.base_type(),
+        Some(DataValueType::Int64 | DataValueType::Uint64 | DataValueType::Dec64)
+    )
+}
 
-        let mut ancestors = node
-            .inclusive_ancestors()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .enumerate();
-        let ancestors_len = ancestors.len();
+/// The JSON type of a leaf's values in Nix-style documents, if it has a
+/// single one.
+fn nix_json_type(node: &SchemaNode) -> Option<&'static str> {
+    let json_type = match node.base_type()? {
+        DataValueType::Int8
+        | DataValueType::Int16
+        | DataValueType::Int32
+        | DataValueType::Uint8
+        | DataValueType::Uint16
+        | DataValueType::Uint32 => "number",
+        DataValueType::Bool => "boolean",
+        DataValueType::Int64
+        | DataValueType::Uint64
+        | DataValueType::Dec64
+        | DataValueType::String
+        | DataValueType::Enum
+        | DataValueType::Binary
+        | DataValueType::Bits
+        | DataValueType::IdentityRef
+        | DataValueType::InstanceId => "string",
+        _ => return None,
+    };
+    Some(json_type)
+}
 
-        for (i, an) in &mut ancestors {
-            let k = if i == 0 {
-                format!("rtbrick-config:{}", an.name())
-            } else {
-                format!("{}", an.name())
-            };
+/// Check that the leaf values of a Nix-style object have the JSON types
+/// yang2nix gives them, before anything is coerced.
+fn assert_types(
+    value: &mut serde_json::Value,
+    path: &str,
+    children: &[SchemaNode],
+    errors: &mut Vec<ConvertError>,
+) {
+    use serde_json::Value;
 
-            p = p
-                .into_iter()
-                .flat_map(|x| x.get_mut(&k).into_iter())
-                .collect();
+    fn assert_type(value: &Value, node: &SchemaNode, path: &str, errors: &mut Vec<ConvertError>) {
+        let actual = match value {
+            Value::Null => return,
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        match nix_json_type(node) {
+            Some(expected) if expected != actual => errors.push(ConvertError::new(
+                path,
+                format!(
+                    "expected a {} for {:?}, found a {}",
+                    expected,
+                    node.base_type().unwrap(),
+                    actual
+                ),
+            )),
+            _ => {}
+        }
+    }
 
-            // last ancestor
-            if i == (ancestors_len - 1) {
-                // we didn't find anything
-                if p.len() == 0 {
-                    break;
+    let Value::Object(object) = value else {
+        return;
+    };
+    for (key, member) in object.iter_mut() {
+        let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+        let Some(child) = children.iter().find(|child| child.name() == name) else {
+            continue;
+        };
+        let path = json_pointer_push(path, key);
+        match child.kind() {
+            SchemaNodeKind::Container => {
+                assert_types(member, &path, &data_children(child.children()), errors)
+            }
+            SchemaNodeKind::List => {
+                let children = data_children(child.children());
+                for (path, entry) in nix_list_entries(member, &path, child) {
+                    assert_types(entry, &path, &children, errors);
+                }
+            }
+            SchemaNodeKind::Leaf => assert_type(member, child, &path, errors),
+            SchemaNodeKind::LeafList => {
+                for (i, el) in member.as_array().into_iter().flatten().enumerate() {
+                    assert_type(el, child, &json_pointer_push(&path, &i.to_string()), errors);
                 }
+            }
+            _ => {}
+        }
+    }
+}
 
-                // last node ; convert
-                //println!("{:?} {:?}", node.path(SchemaPathFormat::DATA), key);
-                for e in &mut p {
-                    match mode {
-                        ConvertMode::Yang2Nix => {
-                            let as_array = if let serde_json::Value::Array(a) = e.take() {
-                                a
-                            } else {
-                                panic!("Expected an array. Are you sure this is a YANG-style file?")
-                            };
-
-                            for mut el in as_array {
-                                let mut p2 = &mut **e; // reference to the value where the element will be inserted
-                                for key in &key_names {
-                                    let k = el
-                                        .as_object_mut()
-                                        .expect("expected an object")
-                                        .remove(key)
-                                        .expect("expected key");
-                                    let k = k
-                                        .as_str()
-                                        .map(|s| s.to_string())
-                                        .or(k
-                                            .as_number()
-                                            .and_then(|n| serde_json::to_string(n).ok()))
-                                        .expect("can not determine key");
-
-                                    if !p2.is_object() {
-                                        *p2 = serde_json::Value::Object(Default::default());
-                                    };
-                                    p2 = p2
-                                        .as_object_mut()
-                                        .unwrap()
-                                        .entry(k)
-                                        .or_insert(serde_json::Value::Null);
-                                }
-                                *p2 = el; // insert element
-                            }
-                        }
-                        ConvertMode::Nix2Yang => {
-                            let mut a = vec![];
-
-                            let mut q: Vec<(Vec<String>, _)> = vec![(vec![], e.take())];
-
-                            while let Some((depth, mut el)) = q.pop() {
-                                if depth.len() == key_names.len() {
-                                    for (key, key_name) in depth.into_iter().zip(node.list_keys()) {
-                                        let key = match key_name.base_type() {
-                                            Some(
-                                                DataValueType::Int8
-                                                | DataValueType::Int16
-                                                | DataValueType::Int32
-                                                | DataValueType::Int64
-                                                | DataValueType::Uint8
-                                                | DataValueType::Uint16
-                                                | DataValueType::Uint32
-                                                | DataValueType::Uint64
-                                                | DataValueType::Dec64,
-                                            ) => serde_json::from_str(&key).unwrap(),
-                                            _ => serde_json::Value::from(key.to_string()),
-                                        };
-                                        el.as_object_mut()
-                                            .expect("expected object")
-                                            .insert(key_name.name().to_string(), key);
-                                    }
-                                    a.push(el);
-                                } else {
-                                    //println!("{:?}", depth);
-                                    //println!("{:?}", el);
-                                    let as_object = if let serde_json::Value::Object(o) = el.take()
-                                    {
-                                        o
-                                    } else {
-                                        panic!("Expected an object. Are you sure this is a Nix-style file?");
-                                    };
-                                    for (key, el2) in as_object {
-                                        let mut depth = depth.clone();
-                                        depth.push(key);
-                                        q.push((depth, el2));
-                                    }
-                                }
-                            }
+/// Coerce the leaf values of a Nix-style object to their RFC 7951 encoding.
+fn coerce_members(
+    value: &mut serde_json::Value,
+    path: &str,
+    children: &[SchemaNode],
+    wide_only: bool,
+    errors: &mut Vec<ConvertError>,
+    report: &mut Report,
+) {
+    fn coerce(
+        value: &mut serde_json::Value,
+        node: &SchemaNode,
+        path: &str,
+        errors: &mut Vec<ConvertError>,
+        report: &mut Report,
+    ) {
+        let before = report.is_enabled().then(|| value.clone());
+        match (coerce_value(value, node), before) {
+            (Err(err), _) => errors.push(ConvertError::new(path, err)),
+            (Ok(()), Some(before)) if before != *value => {
+                report.push(|| ReportItem::new(path, "coerce").before(&before).after(value))
+            }
+            (Ok(()), _) => {}
+        }
+    }
 
-                            **e = serde_json::Value::Array(a);
-                        }
-                    }
+    let object = match value {
+        serde_json::Value::Object(o) => o,
+        _ => return,
+    };
 
-                    //println!("{:?}", e);
+    for (key, member) in object.iter_mut() {
+        let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+        let child = match children.iter().find(|child| child.name() == name) {
+            Some(child) => child,
+            None => continue,
+        };
+        let path = json_pointer_push(path, key);
+        match child.kind() {
+            SchemaNodeKind::Container => coerce_members(
+                member,
+                &path,
+                &data_children(child.children()),
+                wide_only,
+                errors,
+                report,
+            ),
+            SchemaNodeKind::List => {
+                let children = data_children(child.children());
+                for (path, entry) in nix_list_entries(member, &path, child) {
+                    coerce_members(entry, &path, &children, wide_only, errors, report);
                 }
-                //println!("");
-                break;
             }
-            if an.kind() == SchemaNodeKind::List {
-                for _ in an.list_keys() {
-                    p = p
-                        .into_iter()
-                        .flat_map(|x| -> Box<dyn Iterator<Item = &mut serde_json::Value>> {
-                            match x {
-                                serde_json::Value::Array(a) => Box::new(a.into_iter()),
-                                serde_json::Value::Object(o) => Box::new(o.values_mut()),
-                                _ => panic!(),
-                            }
-                        })
-                        .collect();
+            _ if wide_only && !is_wide(child) => {}
+            SchemaNodeKind::Leaf => coerce(member, child, &path, errors, report),
+            SchemaNodeKind::LeafList => {
+                for (i, el) in member.as_array_mut().into_iter().flatten().enumerate() {
+                    let path = json_pointer_push(&path, &i.to_string());
+                    coerce(el, child, &path, errors, report);
                 }
             }
+            _ => {}
+        }
+    }
+}
 
-            if p.len() == 0 {
-                break;
+/// A value as compact JSON with all control characters escaped, not only
+/// those JSON requires to be, and with `ascii` all non-ASCII characters as
+/// well.
+fn json_string(value: &serde_json::Value, ascii: bool) -> String {
+    // such characters can only appear in strings, unescaped
+    let mut json = String::new();
+    for c in value.to_string().chars() {
+        if c.is_control() || (ascii && !c.is_ascii()) {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                write!(json, "\\u{:04x}", unit).unwrap();
             }
+        } else {
+            json.push(c);
         }
     }
+    json
+}
+
+/// Whether a YANG string can hold the character, following the XML `Char`
+/// production RFC 7950 refers to.
+fn yang_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r') || (!c.is_control() && c != '\u{fffe}' && c != '\u{ffff}')
+}
 
-    println!("{}", serde_json::to_string(&data).unwrap());
+/// Check the string values of a document for characters YANG strings can't
+/// hold, stripping them or reporting errors.
+fn check_string_chars(
+    value: &mut serde_json::Value,
+    path: &str,
+    invalid_chars: &InvalidChars,
+    errors: &mut Vec<ConvertError>,
+    report: &mut Report,
+) {
+    use serde_json::Value;
 
-    Ok(())
+    match value {
+        Value::String(s) if !s.chars().all(yang_char) => match invalid_chars {
+            InvalidChars::Error => errors.push(ConvertError::new(
+                path,
+                format!("{:?}: invalid characters for a YANG string", s),
+            )),
+            InvalidChars::Strip => {
+                let before = report.is_enabled().then(|| Value::from(s.as_str()));
+                s.retain(yang_char);
+                eprintln!("warning: {}: stripped invalid characters", path);
+                if let Some(before) = before {
+                    report.push(|| {
+                        ReportItem::new(path, "strip")
+                            .before(&before)
+                            .after(&Value::from(s.as_str()))
+                    });
+                }
+            }
+        },
+        Value::Array(a) => {
+            for (i, el) in a.iter_mut().enumerate() {
+                let path = json_pointer_push(path, &i.to_string());
+                check_string_chars(el, &path, invalid_chars, errors, report);
+            }
+        }
+        Value::Object(o) => {
+            for (key, member) in o.iter_mut() {
+                let path = json_pointer_push(path, key);
+                check_string_chars(member, &path, invalid_chars, errors, report);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Escape a string as a double-quoted Nix string literal.
+///
+/// Returns `None` for control characters, which Nix strings can't express.
+fn nix_string_literal(s: &str) -> Option<String> {
+    let mut literal = String::from("\"");
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => literal.push_str("\\$"),
+            c if c.is_control() => return None,
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    Some(literal)
+}
+
+/// A Nix string expression, decoded from JSON escapes where a literal can't
+/// express the string, or with `ascii` holds non-ASCII characters.
+fn nix_string(s: &str, ascii: bool) -> String {
+    match nix_string_literal(s) {
+        Some(literal) if !ascii || s.is_ascii() => literal,
+        _ => format!(
+            "(builtins.fromJSON {})",
+            nix_string_literal(&json_string(&serde_json::Value::from(s), true)).unwrap()
+        ),
+    }
+}
+
+/// A Nix attribute name, quoted unless it is a plain identifier other than a
+/// keyword.
+fn nix_attr_name(name: &str, ascii: bool) -> String {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+    ];
+    let mut chars = name.chars();
+    let identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+        && !KEYWORDS.contains(&name);
+    match nix_string(name, ascii) {
+        _ if identifier => name.to_string(),
+        // decoded names are interpolated
+        name if name.starts_with('(') => format!("${{{}}}", name),
+        name => name,
+    }
+}
+
+/// A JSON value as a Nix expression, continuing lines at `indent`, with
+/// `ascii` escaping non-ASCII characters in strings.
+fn nix_value(value: &serde_json::Value, indent: &str, ascii: bool) -> String {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        // negative numbers are unary minus applications, which list
+        // elements can't be without parentheses
+        Value::Number(n) if n.as_f64().is_some_and(|n| n < 0.0) => format!("({})", n),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => nix_string(s, ascii),
+        Value::Array(a) if a.is_empty() => "[ ]".to_string(),
+        Value::Array(a) => {
+            let inner = format!("{}  ", indent);
+            let mut out = String::from("[\n");
+            for el in a {
+                writeln!(out, "{}{}", inner, nix_value(el, &inner, ascii)).unwrap();
+            }
+            out + indent + "]"
+        }
+        Value::Object(o) if o.is_empty() => "{ }".to_string(),
+        Value::Object(o) => {
+            let inner = format!("{}  ", indent);
+            let mut out = String::from("{\n");
+            for (key, member) in o {
+                let member = nix_value(member, &inner, ascii);
+                let name = nix_attr_name(key, ascii);
+                writeln!(out, "{}{} = {};", inner, name, member).unwrap();
+            }
+            out + indent + "}"
+        }
+    }
+}
+
+/// Nix literal for the default value of a leaf, if it is a clean literal of
+/// the leaf's base type.
+fn nix_default_literal(node: &SchemaNode) -> Option<String> {
+    let default = node.default_value_canonical()?;
+    let literal = match node.base_type()? {
+        DataValueType::Int8
+        | DataValueType::Int16
+        | DataValueType::Int32
+        | DataValueType::Int64
+        | DataValueType::Uint8
+        | DataValueType::Uint16
+        | DataValueType::Uint32 => default.parse::<i64>().ok().map(|n| n.to_string()),
+        DataValueType::Uint64 => default
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| nix_string_literal(&n.to_string())),
+        DataValueType::Dec64 => default
+            .parse::<f64>()
+            .ok()
+            .filter(|_| {
+                default
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == '.' || c == '-')
+            })
+            .and_then(|_| nix_string_literal(default)),
+        DataValueType::Bool => match default {
+            "true" | "false" => Some(default.to_string()),
+            _ => None,
+        },
+        DataValueType::Enum
+        | DataValueType::Union
+        | DataValueType::String
+        | DataValueType::InstanceId => nix_string_literal(default),
+        _ => None,
+    };
+    if literal.is_none() {
+        eprintln!(
+            "warning: {}: default value {:?} is not a clean literal, ignoring it",
+            node.path(SchemaPathFormat::DATA),
+            default
+        );
+    }
+    literal
+}
+
+/// A Nix type expression as the argument of a type function such as
+/// `listOf`, parenthesized unless it is a single term already.
+fn nix_type_arg(nix_type: &str) -> String {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut atomic = true;
+    for c in nix_type.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => atomic = false,
+            _ => {}
+        }
+    }
+    if atomic {
+        nix_type.to_string()
+    } else {
+        format!("({})", nix_type)
+    }
+}
+
+/// Nix type of a leaf or the elements of a leaf-list, if its type is handled.
+fn nix_type_for(node: &SchemaNode, options: &GenerateOptions) -> Option<String> {
+    let overrides = &options.type_overrides;
+    if let Some(nix_type) = overrides.get(&node.path(SchemaPathFormat::DATA)) {
+        return Some(nix_type.clone());
+    }
+    let nix_type = match node.base_type()? {
+        // the same as ints.u16, so values convert the same
+        DataValueType::Uint16 if options.port_heuristic && is_port(node) => "lib.types.port",
+        DataValueType::Enum => "lib.types.str",
+        DataValueType::Union => "lib.types.str",
+        DataValueType::String => "lib.types.str",
+        DataValueType::Bool => "lib.types.bool",
+        DataValueType::Int8 => "lib.types.ints.s8",
+        DataValueType::Int16 => "lib.types.ints.s16",
+        DataValueType::Int32 => "lib.types.ints.s32",
+        DataValueType::Uint8 => "lib.types.ints.u8",
+        DataValueType::Uint16 => "lib.types.ints.u16",
+        DataValueType::Uint32 => "lib.types.ints.u32",
+        // 64-bit values are kept as strings, as RFC 7951 encodes them, since
+        // Nix integers can't hold all of them
+        DataValueType::Int64 => {
+            "(lib.types.addCheck lib.types.str (s: builtins.match \"-?[0-9]+\" s != null))"
+        }
+        DataValueType::Uint64 => {
+            "(lib.types.addCheck lib.types.str (s: builtins.match \"[0-9]+\" s != null))"
+        }
+        DataValueType::Dec64 => {
+            "(lib.types.addCheck lib.types.str (s: builtins.match \"-?[0-9]+(\\\\.[0-9]+)?\" s != null))"
+        }
+        // instance-identifiers are encoded as absolute XPath strings
+        DataValueType::InstanceId => "(lib.types.addCheck lib.types.str (lib.hasPrefix \"/\"))",
+        _ => return None,
+    };
+    Some(nix_type.to_string())
+}
+
+/// Whether a leaf is named like a TCP or UDP port, e.g. `port`,
+/// `source-port` or `port-number`.
+fn is_port(node: &SchemaNode) -> bool {
+    let name = node.name();
+    name == "port" || name.ends_with("-port") || name.ends_with("port-number")
+}
+
+/// Read a map of data paths to Nix type expressions, from TOML if the file
+/// name says so and JSON otherwise.
+fn read_type_overrides(path: &Path) -> BTreeMap<String, String> {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    let overrides = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(|err| err.to_string()),
+        _ => serde_json::from_str(&text).map_err(|err| err.to_string()),
+    };
+    overrides.unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+/// Print every schema node that the options generator can't map faithfully,
+/// returning how many were found.
+fn probe<'a>(roots: impl Iterator<Item = SchemaNode<'a>>, options: &GenerateOptions) -> usize {
+    let type_overrides = &options.type_overrides;
+    let mut found = 0;
+    for node in roots.flat_map(|root| root.traverse()) {
+        let path = node.path(SchemaPathFormat::DATA);
+        let reason = match node.kind() {
+            SchemaNodeKind::Leaf | SchemaNodeKind::LeafList
+                if type_overrides.contains_key(&path) =>
+            {
+                continue
+            }
+            SchemaNodeKind::Leaf | SchemaNodeKind::LeafList => match node.base_type() {
+                Some(DataValueType::Union) => "union mapped to lib.types.str".to_string(),
+                Some(DataValueType::Enum) => "enum mapped to lib.types.str".to_string(),
+                base_type if nix_type_for(&node, options).is_none() => {
+                    format!("unhandled type {:?}", base_type)
+                }
+                _ => continue,
+            },
+            SchemaNodeKind::Container
+            | SchemaNodeKind::List
+            | SchemaNodeKind::Choice
+            | SchemaNodeKind::Case => continue,
+            kind => format!("unhandled node kind {:?}", kind),
+        };
+        println!("{}: {}", path, reason);
+        found += 1;
+    }
+    found
+}
+
+/// Markdown documentation of the generated options, collected during the same
+/// schema walk that generates them.
+#[derive(Default)]
+struct Readme {
+    /// Heading and table rows of each container and list, in schema order.
+    sections: Vec<(String, Vec<String>)>,
+    /// Sections of the nodes currently being walked.
+    open: Vec<usize>,
+    /// Nix attribute path of the node currently being walked.
+    path: Vec<String>,
+}
+
+impl Readme {
+    fn cell(s: &str) -> String {
+        s.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace('|', "\\|")
+    }
+
+    fn enter(&mut self, node: &SchemaNode) {
+        self.path.push(node.name().to_string());
+        let level = if self.open.is_empty() { "##" } else { "###" };
+        let mut heading = format!("{} `{}`\n", level, self.path.join("."));
+        if let Some(description) = node.description() {
+            writeln!(heading, "\n{}", description.trim()).unwrap();
+        }
+        if node.kind() == SchemaNodeKind::List {
+            if node.is_keyless_list() {
+                self.path.push("*".to_string());
+            }
+            for key in node.list_keys() {
+                self.path.push(format!("<{}>", key.name()));
+            }
+        }
+        self.open.push(self.sections.len());
+        self.sections.push((heading, vec![]));
+    }
+
+    fn leave(&mut self, node: &SchemaNode) {
+        self.open.pop();
+        self.leave_attrs();
+        if node.kind() == SchemaNodeKind::List {
+            for _ in 0..node
+                .list_keys()
+                .count()
+                .max(node.is_keyless_list() as usize)
+            {
+                self.path.pop();
+            }
+        }
+    }
+
+    /// Enter a node that is an attribute set without a section of its own.
+    fn enter_attrs(&mut self, node: &SchemaNode) {
+        self.path.push(node.name().to_string());
+    }
+
+    fn leave_attrs(&mut self) {
+        self.path.pop();
+    }
+
+    fn option(&mut self, node: &SchemaNode, nix_type: &str, default: Option<&str>) {
+        if self.open.is_empty() {
+            self.open.push(self.sections.len());
+            self.sections
+                .push(("## Top-level options\n".to_string(), vec![]));
+        }
+        let mut option = self.path.clone();
+        option.push(node.name().to_string());
+        let row = format!(
+            "| `{}` | `{}` | {} | {} | `{}` |",
+            option.join("."),
+            Self::cell(nix_type),
+            default.map_or("-".to_string(), |default| format!(
+                "`{}`",
+                Self::cell(default)
+            )),
+            Self::cell(node.description().unwrap_or_default()),
+            node.path(SchemaPathFormat::DATA),
+        );
+        let section = *self.open.last().unwrap();
+        self.sections[section].1.push(row);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::from("# Options\n");
+        for (heading, rows) in &self.sections {
+            write!(out, "\n{}", heading).unwrap();
+            if !rows.is_empty() {
+                out += "\n| Option | Type | Default | Description | YANG Path |\n";
+                out += "|---|---|---|---|---|\n";
+                for row in rows {
+                    writeln!(out, "{}", row).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Options of the options generator.
+#[derive(Default)]
+struct GenerateOptions {
+    /// Comment options with the `when` conditions of their nodes. Only
+    /// conditions with a description are commented, since the bindings don't
+    /// expose the XPath expression.
+    when_conditions: bool,
+    /// Comment each option with the data path of its schema node.
+    yang_path_comments: bool,
+    /// Truncate descriptions longer than this many characters.
+    max_description_length: Option<usize>,
+    /// Join the lines of descriptions with spaces, and mark truncated ones
+    /// with a `...` suffix only, as `--description-max-length` does.
+    flatten_descriptions: bool,
+    /// Escape non-ASCII characters in descriptions.
+    ascii: bool,
+    /// Fall back to `lib.types.anything` with a warning for leaf types
+    /// without a mapping, rather than failing with an error.
+    warn_on_todo_types: bool,
+    /// Nix type expressions to use for leaves, by data path, instead of the
+    /// mapping of their YANG type.
+    type_overrides: BTreeMap<String, String>,
+    /// Use `lib.types.port` for uint16 leaves named like ports.
+    port_heuristic: bool,
+}
+
+/// Lines of a node's description for the generated module.
+///
+/// Line endings and tabs are normalized, other control characters,
+/// trailing whitespace and the indentation the continuation lines have in
+/// the YANG source are removed, and the description is truncated to
+/// `--max-description-length` characters. With `flatten_descriptions`, the
+/// lines are joined into one before it is truncated.
+fn description_lines(node: &SchemaNode, options: &GenerateOptions) -> Vec<String> {
+    let description = match node.description() {
+        Some(description) => description
+            .replace("\r\n", "\n")
+            .replace('\r', "\n")
+            .replace('\t', "    ")
+            .replace(|c: char| c.is_control() && c != '\n', ""),
+        None => return vec![],
+    };
+    let mut lines = description.lines().map(str::trim_end).collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    while lines.first().is_some_and(|line| line.is_empty()) {
+        lines.remove(0);
+    }
+    let common = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(0);
+    let mut lines = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => line.trim_start().to_string(),
+            _ => line.get(common..).unwrap_or_default().to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if options.flatten_descriptions {
+        let line = lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let line = match options.max_description_length {
+            Some(max_length) if line.chars().count() > max_length => {
+                let kept = line.chars().take(max_length).collect::<String>();
+                format!("{}...", kept.trim_end())
+            }
+            _ => line,
+        };
+        return vec![line];
+    }
+    if let Some(max_length) = options.max_description_length {
+        let mut length = 0;
+        for i in 0..lines.len() {
+            let line_length = lines[i].chars().count();
+            if length + line_length > max_length {
+                let kept = lines[i]
+                    .chars()
+                    .take(max_length - length)
+                    .collect::<String>();
+                lines[i] = format!("{}…", kept.trim_end());
+                lines.truncate(i + 1);
+                lines.push(format!(
+                    "(truncated, see the description of {})",
+                    node.path(SchemaPathFormat::DATA)
+                ));
+                break;
+            }
+            length += line_length;
+        }
+    }
+    lines
+}
+
+/// Print lines as a Nix indented string, each line indented one level below
+/// `indent`.
+fn print_nix_indented_string(out: &mut String, indent: &str, lines: &[String]) {
+    writeln!(out, "''").unwrap();
+    for line in lines {
+        if line.is_empty() {
+            writeln!(out).unwrap();
+        } else {
+            let line = line.replace("''", "'''").replace("${", "''${");
+            writeln!(out, "{}  {}", indent, line).unwrap();
+        }
+    }
+    write!(out, "{}''", indent).unwrap();
+}
+
+/// Print a comment for each `when` condition of the node.
+///
+/// The bindings don't expose the XPath expression of a condition, so its
+/// description is printed instead, and conditions without one are skipped.
+fn print_when_conditions(out: &mut String, indent: &str, node: &SchemaNode) {
+    for when in node.whens() {
+        let Some(description) = when.description() else {
+            continue;
+        };
+        let condition = description.split_whitespace().collect::<Vec<_>>().join(" ");
+        writeln!(out, "{}# when: {}", indent, condition).unwrap();
+    }
+}
+
+/// Callbacks for a walk over the schema with [`visit_schema`].
+///
+/// Leaves and leaf-lists, including list keys, are passed to `leaf`; all
+/// other callbacks default to doing nothing.
+trait SchemaVisitor {
+    fn enter_container(&mut self, _node: &SchemaNode) {}
+    fn leave_container(&mut self, _node: &SchemaNode) {}
+    fn enter_list(&mut self, _node: &SchemaNode) {}
+    fn leave_list(&mut self, _node: &SchemaNode) {}
+    fn enter_choice(&mut self, _node: &SchemaNode) {}
+    fn leave_choice(&mut self, _node: &SchemaNode) {}
+    fn enter_case(&mut self, _node: &SchemaNode) {}
+    fn leave_case(&mut self, _node: &SchemaNode) {}
+    fn leaf(&mut self, _node: &SchemaNode) {}
+}
+
+/// Whether a case is the implicit one libyang compiles a choice's shorthand
+/// case into, i.e. a leaf, container or the like directly under the choice.
+///
+/// The bindings don't tell implicit cases apart, so this goes by their shape:
+/// a single child named like the case. An explicit case of that shape is
+/// treated the same, which only saves it a level of nesting.
+fn is_shorthand_case(node: &SchemaNode) -> bool {
+    let mut children = node.children();
+    match (children.next(), children.next()) {
+        (Some(child), None) => child.name() == node.name(),
+        _ => false,
+    }
+}
+
+/// Walk the schema below the given roots depth first, in schema order.
+fn visit_schema<'a>(roots: impl Iterator<Item = SchemaNode<'a>>, visitor: &mut impl SchemaVisitor) {
+    for node in roots {
+        match node.kind() {
+            SchemaNodeKind::Container => {
+                visitor.enter_container(&node);
+                visit_schema(node.children(), visitor);
+                visitor.leave_container(&node);
+            }
+            SchemaNodeKind::List => {
+                visitor.enter_list(&node);
+                visit_schema(node.children(), visitor);
+                visitor.leave_list(&node);
+            }
+            SchemaNodeKind::Choice => {
+                visitor.enter_choice(&node);
+                visit_schema(node.children(), visitor);
+                visitor.leave_choice(&node);
+            }
+            // the implicit case isn't a level of its own
+            SchemaNodeKind::Case if is_shorthand_case(&node) => {
+                visit_schema(node.children(), visitor);
+            }
+            SchemaNodeKind::Case => {
+                visitor.enter_case(&node);
+                visit_schema(node.children(), visitor);
+                visitor.leave_case(&node);
+            }
+            SchemaNodeKind::Leaf | SchemaNodeKind::LeafList => visitor.leaf(&node),
+            // opaque data has no option type to map to
+            SchemaNodeKind::AnyData => eprintln!(
+                "warning: {}: skipping anydata/anyxml",
+                node.path(SchemaPathFormat::DATA)
+            ),
+            // operations and notifications aren't configuration
+            SchemaNodeKind::Rpc
+            | SchemaNodeKind::Action
+            | SchemaNodeKind::Input
+            | SchemaNodeKind::Output
+            | SchemaNodeKind::Notification => {}
+        }
+    }
+}
+
+/// Whether and how the option of a leaf may be left unset.
+#[derive(Debug, PartialEq)]
+enum Optionality {
+    Required,
+    /// Not nullable, defaulting to this Nix literal.
+    Default(String),
+    /// Nullable, but required along with the other options of the named case.
+    Nullable(Option<String>),
+}
+
+/// Optionality of the option of a leaf:
+///
+/// - list keys are required, as every entry has them,
+/// - with a default that is a clean literal, it takes that default,
+/// - if mandatory and all choices it is in are mandatory, it is required,
+/// - if mandatory within a case of an optional choice, it is nullable but
+///   must be set along with the other options of the case,
+/// - otherwise it is nullable.
+fn leaf_optionality(node: &SchemaNode) -> Optionality {
+    if node.is_list_key() {
+        return Optionality::Required;
+    }
+    if node.has_default() {
+        if let Some(default) = nix_default_literal(node) {
+            return Optionality::Default(default);
+        }
+    }
+    if !node.is_mandatory() {
+        return Optionality::Nullable(None);
+    }
+    let ancestors = node
+        .ancestors()
+        .take_while(|an| matches!(an.kind(), SchemaNodeKind::Choice | SchemaNodeKind::Case))
+        .collect::<Vec<_>>();
+    let optional_choice = ancestors
+        .iter()
+        .any(|an| an.kind() == SchemaNodeKind::Choice && !an.is_mandatory());
+    if !optional_choice {
+        return Optionality::Required;
+    }
+    let case = ancestors
+        .iter()
+        .find(|an| an.kind() == SchemaNodeKind::Case)
+        .map(|case| case.name().to_string());
+    Optionality::Nullable(case)
+}
+
+/// The option generated for a leaf or leaf-list.
+struct LeafOption {
+    optionality: Optionality,
+    nix_type: String,
+    default: Option<String>,
+}
+
+fn leaf_option(node: &SchemaNode, options: &GenerateOptions) -> LeafOption {
+    let leaf_type = nix_type_for(node, options).unwrap_or_else(|| {
+        if !options.warn_on_todo_types {
+            eprintln!(
+                "{}: no Nix type for {:?}, see --warn-on-todo-types and --type-overrides",
+                node.path(SchemaPathFormat::DATA),
+                node.base_type()
+            );
+            std::process::exit(1);
+        }
+        eprintln!(
+            "warning: {}: no Nix type for {:?}, using lib.types.anything",
+            node.path(SchemaPathFormat::DATA),
+            node.base_type()
+        );
+        "lib.types.anything".to_string()
+    });
+    let optionality = match node.kind() {
+        SchemaNodeKind::Leaf => leaf_optionality(node),
+        _ => Optionality::Nullable(None),
+    };
+    let nix_type = match (node.kind(), &optionality) {
+        (SchemaNodeKind::Leaf, Optionality::Nullable(_)) => {
+            format!("lib.types.nullOr {}", nix_type_arg(&leaf_type))
+        }
+        (SchemaNodeKind::Leaf, _) => leaf_type.to_string(),
+        (SchemaNodeKind::LeafList, _) => format!("lib.types.listOf {}", nix_type_arg(&leaf_type)),
+        _ => unreachable!(),
+    };
+    let default = match (node.kind(), &optionality) {
+        (SchemaNodeKind::Leaf, Optionality::Default(default)) => Some(default.clone()),
+        (SchemaNodeKind::Leaf, Optionality::Nullable(_)) => Some("null".to_string()),
+        (SchemaNodeKind::LeafList, _) => Some("[]".to_string()),
+        _ => None,
+    };
+    LeafOption {
+        optionality,
+        nix_type,
+        default,
+    }
+}
+
+/// Collects what the options generator makes of each schema node as JSON,
+/// for debugging the mapping and for other tools.
+struct IrDumper<'o> {
+    options: &'o GenerateOptions,
+    /// Nix attribute path of the node currently being visited.
+    path: Vec<String>,
+    nodes: Vec<serde_json::Value>,
+}
+
+impl IrDumper<'_> {
+    fn node(&self, node: &SchemaNode, kind: &str) -> serde_json::Value {
+        let mut option = self.path.clone();
+        option.push(node.name().to_string());
+        serde_json::json!({
+            "kind": kind,
+            "option": option.join("."),
+            "nix": option,
+            "yang_path": node.path(SchemaPathFormat::DATA),
+            "module": node.module().name(),
+            "description": node.description(),
+            "config": node.is_config(),
+            "mandatory": node.is_mandatory(),
+            "transformations": [],
+        })
+    }
+
+    fn enter(&mut self, node: &SchemaNode, kind: &str) {
+        let entry = self.node(node, kind);
+        self.nodes.push(entry);
+        self.path.push(node.name().to_string());
+    }
+
+    fn leave(&mut self) {
+        self.path.pop();
+    }
+}
+
+impl SchemaVisitor for IrDumper<'_> {
+    fn enter_container(&mut self, node: &SchemaNode) {
+        self.enter(node, "container");
+    }
+
+    fn leave_container(&mut self, _node: &SchemaNode) {
+        self.leave();
+    }
+
+    fn enter_list(&mut self, node: &SchemaNode) {
+        let mut entry = self.node(node, "list");
+        let keys = node.list_keys().map(|key| key.name().to_string());
+        entry["keys"] = keys.collect::<Vec<_>>().into();
+        entry["transformations"] = if node.is_keyless_list() {
+            serde_json::json!(["listOf-submodule"])
+        } else {
+            serde_json::json!(["attrsOf-per-key"])
+        };
+        self.nodes.push(entry);
+        self.path.push(node.name().to_string());
+        if node.is_keyless_list() {
+            self.path.push("*".to_string());
+        }
+        for key in node.list_keys() {
+            self.path.push(format!("<{}>", key.name()));
+        }
+    }
+
+    fn leave_list(&mut self, node: &SchemaNode) {
+        for _ in 0..node
+            .list_keys()
+            .count()
+            .max(node.is_keyless_list() as usize)
+        {
+            self.path.pop();
+        }
+        self.leave();
+    }
+
+    fn enter_choice(&mut self, node: &SchemaNode) {
+        self.enter(node, "choice");
+        self.nodes.last_mut().unwrap()["transformations"] = serde_json::json!(["no-data-node"]);
+    }
+
+    fn leave_choice(&mut self, _node: &SchemaNode) {
+        self.leave();
+    }
+
+    fn enter_case(&mut self, node: &SchemaNode) {
+        self.enter(node, "case");
+        self.nodes.last_mut().unwrap()["transformations"] = serde_json::json!(["no-data-node"]);
+    }
+
+    fn leave_case(&mut self, _node: &SchemaNode) {
+        self.leave();
+    }
+
+    fn leaf(&mut self, node: &SchemaNode) {
+        let kind = match node.kind() {
+            SchemaNodeKind::Leaf => "leaf",
+            _ => "leaf-list",
+        };
+        let mut entry = self.node(node, kind);
+        entry["base_type"] = node.base_type().map(|t| format!("{:?}", t)).into();
+        entry["type"] = node.base_type().map(yang_type_name).into();
+        if node.is_list_key() {
+            entry["transformations"] = serde_json::json!(["key-as-attribute-name"]);
+            self.nodes.push(entry);
+            return;
+        }
+        let LeafOption {
+            optionality,
+            nix_type,
+            default,
+        } = leaf_option(node, self.options);
+        entry["nix_type"] = nix_type.into();
+        entry["default"] = default.into();
+        entry["optionality"] = match optionality {
+            Optionality::Required => "required".into(),
+            Optionality::Default(_) => "default".into(),
+            Optionality::Nullable(None) => "nullable".into(),
+            Optionality::Nullable(Some(case)) => serde_json::json!({ "required-with-case": case }),
+        };
+        if is_wide(node) {
+            entry["transformations"] = serde_json::json!(["64-bit-as-string"]);
+        }
+        self.nodes.push(entry);
+    }
+}
+
+/// Name of a built-in YANG type.
+fn yang_type_name(base_type: DataValueType) -> &'static str {
+    match base_type {
+        DataValueType::Unknown => "unknown",
+        DataValueType::Binary => "binary",
+        DataValueType::Uint8 => "uint8",
+        DataValueType::Uint16 => "uint16",
+        DataValueType::Uint32 => "uint32",
+        DataValueType::Uint64 => "uint64",
+        DataValueType::String => "string",
+        DataValueType::Bits => "bits",
+        DataValueType::Bool => "boolean",
+        DataValueType::Dec64 => "decimal64",
+        DataValueType::Empty => "empty",
+        DataValueType::Enum => "enumeration",
+        DataValueType::IdentityRef => "identityref",
+        DataValueType::InstanceId => "instance-identifier",
+        DataValueType::LeafRef => "leafref",
+        DataValueType::Union => "union",
+        DataValueType::Int8 => "int8",
+        DataValueType::Int16 => "int16",
+        DataValueType::Int32 => "int32",
+        DataValueType::Int64 => "int64",
+    }
+}
+
+/// Records mapping the Nix attribute path of every generated option to the
+/// data path of its schema node, for tooling translating between the two.
+///
+/// List entries appear as `<key>` placeholders in the attribute paths (`*`
+/// for keyless lists), in the order the converter nests them.
+fn option_mapping<'a>(
+    roots: impl Iterator<Item = SchemaNode<'a>>,
+    options: &GenerateOptions,
+) -> Vec<serde_json::Value> {
+    let mut dumper = IrDumper {
+        options,
+        path: vec![],
+        nodes: vec![],
+    };
+    visit_schema(roots, &mut dumper);
+    dumper
+        .nodes
+        .into_iter()
+        .map(|node| {
+            let mut record = serde_json::json!({
+                "nix": node["nix"],
+                "yang": node["yang_path"],
+                "kind": node["kind"],
+                "transformations": node["transformations"],
+            });
+            if !node["type"].is_null() {
+                record["type"] = node["type"].clone();
+            }
+            if !node["keys"].is_null() {
+                record["keys"] = node["keys"].clone();
+            }
+            record
+        })
+        .collect()
+}
+
+/// Key orders of the lists in a mapping file written by `--emit-mapping`,
+/// warning about records of data paths the schema doesn't have.
+fn read_mapping(ctx: &Context, path: &Path) -> BTreeMap<String, Vec<String>> {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    let records: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    let mut key_orders = BTreeMap::new();
+    for record in &records {
+        let (Some(kind), Some(yang)) = (record["kind"].as_str(), record["yang"].as_str()) else {
+            continue;
+        };
+        if kind != "list" {
+            continue;
+        }
+        if ctx.find_path(yang).is_err() {
+            eprintln!(
+                "warning: {}: no list {} in the schema",
+                path.display(),
+                yang
+            );
+            continue;
+        }
+        let keys = record["keys"].as_array().into_iter().flatten();
+        let keys = keys.filter_map(|key| key.as_str().map(str::to_string));
+        key_orders.insert(yang.to_string(), keys.collect());
+    }
+    key_orders
+}
+
+/// Nix function checking the number of entries of a list option against the
+/// list's min-elements and max-elements, if it has any.
+fn nix_list_bounds_check(node: &SchemaNode) -> Option<String> {
+    let min = node.min_elements().filter(|min| *min > 0);
+    let max = node.max_elements();
+    if min.is_none() && max.is_none() {
+        return None;
+    }
+
+    // keyed lists have their entries nested once per key
+    fn count(value: &str, depth: usize) -> String {
+        match depth {
+            1 => format!("builtins.length (builtins.attrNames {})", value),
+            _ => {
+                let v = format!("v{}", depth);
+                format!(
+                    "lib.foldl' (n: {}: n + {}) 0 (builtins.attrValues {})",
+                    v,
+                    count(&v, depth - 1),
+                    value
+                )
+            }
+        }
+    }
+    let count = match node.list_keys().count() {
+        0 => "builtins.length x".to_string(),
+        keys => count("x", keys),
+    };
+    let bounds = min
+        .map(|min| format!("n >= {}", min))
+        .into_iter()
+        .chain(max.map(|max| format!("n <= {}", max)))
+        .collect::<Vec<_>>();
+    Some(format!("x: let n = {}; in {}", count, bounds.join(" && ")))
+}
+
+/// A step on the way from the root of the generated options to an option.
+enum OptionStep {
+    Attr(String),
+    /// Into each entry of a list with this many keys, none if keyless.
+    Entries(usize),
+}
+
+/// A Nix assertion that a leaf, mandatory within a case of an optional
+/// choice, is set whenever another leaf or leaf-list of its case is.
+///
+/// `path` leads from `root` to the attribute set of the case; entries of
+/// lists on the way are checked with `lib.all`. There is no assertion if the
+/// case has nothing else that may be set.
+fn case_assertion(
+    root: &str,
+    path: &[OptionStep],
+    node: &SchemaNode,
+    case: &str,
+    ascii: bool,
+) -> Option<String> {
+    let mut base = root.to_string();
+    let mut open = String::new();
+    let mut close = String::new();
+    for step in path {
+        match step {
+            OptionStep::Attr(name) => base = format!("{}.{}", base, name),
+            OptionStep::Entries(keys) => {
+                let mut entries = base;
+                for i in 0..*keys {
+                    entries = match i {
+                        0 => format!("lib.attrValues {}", entries),
+                        _ => format!("lib.concatMap lib.attrValues ({})", entries),
+                    };
+                }
+                open += "lib.all (entry: ";
+                close = format!(") ({}){}", entries, close);
+                base = "entry".to_string();
+            }
+        }
+    }
+    let siblings = node
+        .ancestors()
+        .next()?
+        .children()
+        .filter(|sibling| sibling.name() != node.name() && !sibling.is_list_key())
+        .filter_map(|sibling| match sibling.kind() {
+            SchemaNodeKind::Leaf => match leaf_optionality(&sibling) {
+                Optionality::Nullable(_) => Some((sibling, "null")),
+                _ => None,
+            },
+            SchemaNodeKind::LeafList => Some((sibling, "[ ]")),
+            _ => None,
+        })
+        .map(|(sibling, unset)| {
+            let name = nix_attr_name(sibling.name(), ascii);
+            format!("{}.{} != {}", base, name, unset)
+        })
+        .collect::<Vec<_>>();
+    if siblings.is_empty() {
+        return None;
+    }
+    let name = nix_attr_name(node.name(), ascii);
+    let message = format!(
+        "{} must be set when other options of case {} are set",
+        node.path(SchemaPathFormat::DATA),
+        case
+    );
+    Some(format!(
+        "{{ assertion = {}({}) -> {}.{} != null{}; message = {}; }}",
+        open,
+        siblings.join(" || "),
+        base,
+        name,
+        close,
+        nix_string(&message, ascii)
+    ))
+}
+
+/// Prints the NixOS module options for the visited schema.
+struct NixOptionsPrinter<'o> {
+    out: String,
+    indent: String,
+    options: &'o GenerateOptions,
+    readme: &'o mut Option<Readme>,
+    /// Path from the root of the options to the current node.
+    path: Vec<OptionStep>,
+    /// Assertions of case-mandatory leaves, collected only for output that
+    /// is a module and so has `config.assertions`.
+    assertions: Option<Vec<String>>,
+}
+
+impl NixOptionsPrinter<'_> {
+    fn indent(&mut self) {
+        self.indent += "  ";
+    }
+
+    fn dedent(&mut self) {
+        self.indent = self.indent.chars().skip(2).collect();
+    }
+
+    /// Open a plain attribute set, for choices and cases.
+    fn enter_attrs(&mut self, node: &SchemaNode) {
+        writeln!(self.out).unwrap();
+        for line in description_lines(node, self.options) {
+            writeln!(self.out, "{}# {}", self.indent, line).unwrap();
+        }
+        let name = nix_attr_name(node.name(), self.options.ascii);
+        writeln!(self.out, "{}{} = {{", self.indent, name).unwrap();
+        self.path.push(OptionStep::Attr(name));
+        if let Some(readme) = self.readme {
+            readme.enter_attrs(node);
+        }
+        self.indent();
+    }
+
+    fn leave_attrs(&mut self) {
+        self.path.pop();
+        self.dedent();
+        if let Some(readme) = self.readme {
+            readme.leave_attrs();
+        }
+        writeln!(self.out, "{}}};", self.indent).unwrap();
+    }
+}
+
+impl SchemaVisitor for NixOptionsPrinter<'_> {
+    fn enter_container(&mut self, node: &SchemaNode) {
+        let lines = description_lines(node, self.options);
+        if !lines.is_empty() {
+            writeln!(self.out).unwrap();
+        }
+        for line in lines {
+            writeln!(self.out, "{}# {}", self.indent, line).unwrap();
+        }
+        if self.options.when_conditions {
+            print_when_conditions(&mut self.out, &self.indent, node);
+        }
+        let name = nix_attr_name(node.name(), self.options.ascii);
+        writeln!(self.out, "{}{} = {{", self.indent, name).unwrap();
+        self.path.push(OptionStep::Attr(name));
+        if let Some(readme) = self.readme {
+            readme.enter(node);
+        }
+        self.indent();
+    }
+
+    fn leave_container(&mut self, node: &SchemaNode) {
+        self.path.pop();
+        self.dedent();
+        if let Some(readme) = self.readme {
+            readme.leave(node);
+        }
+        writeln!(self.out, "{}}};", self.indent).unwrap();
+    }
+
+    // Keyed lists become attrsets nested once per key, the key values
+    // being the attribute names. The key leaves are therefore not
+    // options of the entries themselves; `nix2yang` puts them back.
+    // Keyless lists stay lists.
+    fn enter_list(&mut self, node: &SchemaNode) {
+        let (out, indent) = (&mut self.out, &self.indent);
+        writeln!(out).unwrap();
+        if self.options.when_conditions {
+            print_when_conditions(out, indent, node);
+        }
+        if self.options.yang_path_comments {
+            writeln!(
+                out,
+                "{}# yang-path: {}",
+                indent,
+                node.path(SchemaPathFormat::DATA)
+            )
+            .unwrap();
+        }
+        let name = nix_attr_name(node.name(), self.options.ascii);
+        writeln!(out, "{}{} = lib.mkOption {{", indent, name).unwrap();
+        self.path.push(OptionStep::Attr(name));
+        self.path
+            .push(OptionStep::Entries(node.list_keys().count()));
+        if let Some(readme) = self.readme {
+            readme.enter(node);
+        }
+        self.indent();
+        let (out, indent) = (&mut self.out, &self.indent);
+
+        let mut lines = description_lines(node, self.options);
+        for (i, key) in node.list_keys().enumerate() {
+            lines.push(format!("Key {}: {}", i + 1, key.name()));
+        }
+        if node.list_keys().next().is_some() {
+            lines.push(
+                "Entries are attribute sets nested once per key, named by the key values."
+                    .to_string(),
+            );
+        }
+        if let Some(min) = node.min_elements().filter(|min| *min > 0) {
+            lines.push(format!("At least {} entries.", min));
+        }
+        if let Some(max) = node.max_elements() {
+            lines.push(format!("At most {} entries.", max));
+        }
+        write!(out, "{}description = ", indent).unwrap();
+        if self.options.ascii && lines.iter().any(|line| !line.is_ascii()) {
+            write!(out, "{}", nix_string(&lines.join("\n"), true)).unwrap();
+        } else {
+            print_nix_indented_string(out, indent, &lines);
+        }
+        writeln!(out, ";").unwrap();
+
+        write!(out, "{}type = ", indent).unwrap();
+        if nix_list_bounds_check(node).is_some() {
+            write!(out, "lib.types.addCheck (").unwrap();
+        }
+        if node.is_keyless_list() {
+            write!(out, "lib.types.listOf (").unwrap();
+        }
+        for _ in node.list_keys() {
+            write!(out, "lib.types.attrsOf (").unwrap();
+        }
+        writeln!(out, "lib.types.submodule {{\n").unwrap();
+        self.indent();
+        writeln!(self.out, "{}options = {{", self.indent).unwrap();
+        self.indent();
+    }
+
+    fn leave_list(&mut self, node: &SchemaNode) {
+        self.path.truncate(self.path.len() - 2);
+        if let Some(readme) = self.readme {
+            readme.leave(node);
+        }
+        self.dedent();
+        writeln!(self.out, "\n{}}};", self.indent).unwrap();
+        self.dedent();
+        let (out, indent) = (&mut self.out, &self.indent);
+        write!(out, "\n{}}}", indent).unwrap();
+        if node.is_keyless_list() {
+            write!(out, ")").unwrap();
+        }
+        for _ in node.list_keys() {
+            write!(out, ")").unwrap();
+        }
+        if let Some(check) = nix_list_bounds_check(node) {
+            write!(out, ") ({})", check).unwrap();
+        }
+        writeln!(out, ";").unwrap();
+        if node.is_keyless_list() {
+            writeln!(out, "\n{}default = [];", indent).unwrap();
+        } else {
+            writeln!(out, "\n{}default = {{}};", indent).unwrap();
+        }
+        self.dedent();
+        writeln!(self.out, "{}}};", self.indent).unwrap();
+    }
+
+    fn enter_choice(&mut self, node: &SchemaNode) {
+        self.enter_attrs(node);
+    }
+
+    fn leave_choice(&mut self, _node: &SchemaNode) {
+        self.leave_attrs();
+    }
+
+    fn enter_case(&mut self, node: &SchemaNode) {
+        self.enter_attrs(node);
+    }
+
+    fn leave_case(&mut self, _node: &SchemaNode) {
+        self.leave_attrs();
+    }
+
+    fn leaf(&mut self, node: &SchemaNode) {
+        // the key values name the entries instead
+        if node.is_list_key() {
+            return;
+        }
+        let (out, indent) = (&mut self.out, &self.indent);
+        writeln!(out).unwrap();
+        if self.options.when_conditions {
+            print_when_conditions(out, indent, node);
+        }
+        if self.options.yang_path_comments {
+            writeln!(
+                out,
+                "{}# yang-path: {}",
+                indent,
+                node.path(SchemaPathFormat::DATA)
+            )
+            .unwrap();
+        }
+        let name = nix_attr_name(node.name(), self.options.ascii);
+        writeln!(out, "{}{} = lib.mkOption {{", indent, name).unwrap();
+        let LeafOption {
+            optionality,
+            nix_type,
+            default,
+        } = leaf_option(node, self.options);
+        let note = match &optionality {
+            Optionality::Required => Some("Mandatory.".to_string()),
+            Optionality::Nullable(Some(case)) => Some(format!(
+                "Must be set when other options of case {} are set.",
+                case
+            )),
+            _ => None,
+        };
+        let mut lines = description_lines(node, self.options);
+        match (lines.last_mut(), note) {
+            (Some(line), Some(note)) => *line = format!("{} {}", line, note),
+            (None, Some(note)) => lines.push(note),
+            (_, None) => {}
+        }
+        match &lines[..] {
+            [] => {}
+            lines if self.options.ascii && lines.iter().any(|line| !line.is_ascii()) => {
+                let description = nix_string(&lines.join("\n"), true);
+                writeln!(out, "{}  description = {};", indent, description).unwrap();
+            }
+            [line] if nix_string_literal(line).is_some() => {
+                let literal = nix_string_literal(line).unwrap();
+                writeln!(out, "{}  description = {};", indent, literal).unwrap();
+            }
+            lines => {
+                write!(out, "{}  description = ", indent).unwrap();
+                print_nix_indented_string(out, &format!("{}  ", indent), lines);
+                writeln!(out, ";").unwrap();
+            }
+        }
+        writeln!(out, "{}  type = {};", indent, nix_type).unwrap();
+        if let Some(default) = &default {
+            writeln!(out, "{}  default = {};", indent, default).unwrap();
+        }
+        if let Some(readme) = self.readme {
+            readme.option(node, &nix_type, default.as_deref());
+        }
+        writeln!(out, "{}}};", indent).unwrap();
+        if let (Some(assertions), Optionality::Nullable(Some(case))) =
+            (&mut self.assertions, &optionality)
+        {
+            let ascii = self.options.ascii;
+            assertions.extend(case_assertion("cfg", &self.path, node, case, ascii));
+        }
+    }
+}
+
+/// Builds a Nix file of options one schema root at a time, for callers
+/// that walk the schema themselves and may stop early.
+struct NixOptionsBuilder<'o> {
+    printer: NixOptionsPrinter<'o>,
+    /// Closes what the header at the start of `printer.out` opened, but for
+    /// the final brace, which follows the assertions of a module.
+    footer: String,
+}
+
+impl<'o> NixOptionsBuilder<'o> {
+    fn new(options: &'o GenerateOptions, readme: &'o mut Option<Readme>) -> Self {
+        NixOptionsBuilder {
+            printer: NixOptionsPrinter {
+                out: String::from("{ lib, ... }: {\n"),
+                indent: "  ".to_string(),
+                options,
+                readme,
+                path: vec![],
+                assertions: None,
+            },
+            footer: String::new(),
+        }
+    }
+
+    /// A home-manager module declaring the options under `options.<name>`
+    /// and writing their values to `~/.config/<name>/config.json`, with an
+    /// assertion for each leaf mandatory within a case of an optional choice.
+    ///
+    /// The file holds the yang2nix form of the values, with keyed lists as
+    /// attribute sets and the attribute sets of choices and cases, not RFC
+    /// 7951 JSON: consumers expecting YANG JSON must convert it with
+    /// `nix2yang` first, which the generated module says in a comment.
+    fn home_manager(
+        options: &'o GenerateOptions,
+        readme: &'o mut Option<Readme>,
+        name: &str,
+    ) -> Self {
+        let attr = nix_attr_name(name, false);
+        let file = nix_string(&format!(".config/{}/config.json", name), false);
+        NixOptionsBuilder {
+            printer: NixOptionsPrinter {
+                out: format!(
+                    "{{ config, lib, ... }}:\nlet\n  cfg = config.{};\nin {{\n  options.{} = {{\n",
+                    attr, attr
+                ),
+                indent: "    ".to_string(),
+                options,
+                readme,
+                path: vec![],
+                assertions: Some(vec![]),
+            },
+            // Nix can't run nix2yang, so this is the yang2nix form
+            footer: format!(
+                "  }};\n\n  \
+                 # The yang2nix form of the values, not RFC 7951 JSON: convert it with\n  \
+                 # `nix-yang-tools convert --nix2yang` for tools expecting YANG data.\n  \
+                 config.home.file.{}.text =\n    \
+                 builtins.toJSON (lib.filterAttrsRecursive (_: value: value != null) cfg);\n",
+                file
+            ),
+        }
+    }
+
+    /// Append the options of a schema root and everything below it.
+    fn add_node(&mut self, node: SchemaNode) -> &mut Self {
+        visit_schema(std::iter::once(node), &mut self.printer);
+        self
+    }
+
+    /// Append the options of all schema roots of a module, nested under an
+    /// attribute named after the module.
+    fn add_module(&mut self, module: &SchemaModule) -> &mut Self {
+        let printer = &mut self.printer;
+        let name = nix_attr_name(module.name(), printer.options.ascii);
+        writeln!(printer.out, "\n{}{} = {{", printer.indent, name).unwrap();
+        printer.path.push(OptionStep::Attr(name));
+        if let Some(readme) = printer.readme {
+            readme.path.push(module.name().to_string());
+        }
+        printer.indent();
+        visit_schema(module.data(), printer);
+        printer.dedent();
+        printer.path.pop();
+        if let Some(readme) = printer.readme {
+            readme.leave_attrs();
+        }
+        writeln!(printer.out, "{}}};", printer.indent).unwrap();
+        self
+    }
+
+    /// Append the options of the given modules, each nested under its name
+    /// if `namespaced`, or otherwise all merged at the top level.
+    fn add_modules(&mut self, modules: &[SchemaModule], namespaced: bool) -> &mut Self {
+        for module in modules {
+            if namespaced {
+                self.add_module(module);
+            } else {
+                for root in module.data() {
+                    self.add_node(root);
+                }
+            }
+        }
+        self
+    }
+
+    /// The Nix file with the options of all nodes added so far.
+    fn finish(&self) -> String {
+        let mut out = format!("{}{}", self.printer.out, self.footer);
+        if let Some(assertions) = self.printer.assertions.as_ref().filter(|a| !a.is_empty()) {
+            writeln!(out, "\n  config.assertions = [").unwrap();
+            for assertion in assertions {
+                writeln!(out, "    {}", assertion).unwrap();
+            }
+            writeln!(out, "  ];").unwrap();
+        }
+        out + "}\n"
+    }
+}
+
+/// A Nix file with the options for the given schema roots.
+fn nix_options_file<'a>(
+    roots: impl Iterator<Item = SchemaNode<'a>>,
+    options: &GenerateOptions,
+    readme: &mut Option<Readme>,
+) -> String {
+    let mut builder = NixOptionsBuilder::new(options, readme);
+    for root in roots {
+        builder.add_node(root);
+    }
+    builder.finish()
+}
+
+/// Pipe generated Nix code through a formatter command, e.g. `nixfmt`.
+fn format_nix(command: &str, code: &str) -> std::io::Result<String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut words = command.split_whitespace();
+    let program = words.next().unwrap_or_default();
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(code.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            command, output.status
+        )));
+    }
+    String::from_utf8(output.stdout).map_err(std::io::Error::other)
+}
+
+/// Line diff of two texts, with `-` lines of the first and `+` lines of the
+/// second.
+///
+/// Only the lines between the common prefix and suffix are compared, and
+/// if there are too many of them for a longest common subsequence, all of
+/// them are printed as changed.
+fn line_diff(a: &str, b: &str) -> String {
+    let a = a.lines().collect::<Vec<_>>();
+    let b = b.lines().collect::<Vec<_>>();
+    let prefix = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let mut out = format!("@@ line {} @@\n", prefix + 1);
+    if a.len() * b.len() > MAX_LCS_CELLS {
+        for line in a {
+            writeln!(out, "-{}", line).unwrap();
+        }
+        for line in b {
+            writeln!(out, "+{}", line).unwrap();
+        }
+        return out;
+    }
+    for (op, line) in lcs_diff(a, b) {
+        writeln!(out, "{}{}", op, line).unwrap();
+    }
+    out
+}
+
+/// The most cells of the table of [`lcs_diff`] that diffs are computed with,
+/// 10M cells being about 40 MB.
+const MAX_LCS_CELLS: usize = 10_000_000;
+
+/// Diff of two sequences along their longest common subsequence, as items
+/// marked ` ` if common, `-` if only in `a` and `+` if only in `b`.
+fn lcs_diff<'a, T: PartialEq>(a: &'a [T], b: &'a [T]) -> Vec<(char, &'a T)> {
+    // lengths of the longest common subsequences of the suffixes
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            diff.push((' ', &a[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(('-', &a[i]));
+            i += 1;
+        } else {
+            diff.push(('+', &b[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Path of the leaf-list of a leaf-list entry's path.
+fn leaf_list_path(path: &str) -> &str {
+    path.rfind("[.=").map_or(path, |i| &path[..i])
+}
+
+/// Changes of the values of a leaf-list between two data trees, as values
+/// marked `-` or `+`: their set difference, or a positional diff for
+/// user-ordered leaf-lists.
+fn leaf_list_changes(
+    dtree1: &DataTree,
+    dtree2: &DataTree,
+    path: &str,
+    ordered: bool,
+) -> Vec<(char, String)> {
+    use yang2::data::Data;
+
+    let values = |dtree: &DataTree| {
+        dtree
+            .find_xpath(path)
+            .map(|set| set.filter_map(|dnode| dnode.value_canonical()).collect())
+            .unwrap_or_else(|_| vec![])
+    };
+    value_changes(&values(dtree1), &values(dtree2), ordered)
+}
+
+/// Changes between the values of a leaf-list, see [`leaf_list_changes`].
+///
+/// Only the values between the common prefix and suffix are compared, and
+/// if there are too many of them for a longest common subsequence, all of
+/// them are changed.
+fn value_changes(old: &[String], new: &[String], ordered: bool) -> Vec<(char, String)> {
+    if ordered {
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let old = &old[prefix..old.len() - suffix];
+        let new = &new[prefix..new.len() - suffix];
+        if old.len() * new.len() > MAX_LCS_CELLS {
+            let removed = old.iter().map(|value| ('-', value.clone()));
+            return removed
+                .chain(new.iter().map(|value| ('+', value.clone())))
+                .collect();
+        }
+        return lcs_diff(old, new)
+            .into_iter()
+            .filter(|(op, _)| *op != ' ')
+            .map(|(op, value)| (op, value.clone()))
+            .collect();
+    }
+    let old_values = old.iter().collect::<std::collections::HashSet<_>>();
+    let new_values = new.iter().collect::<std::collections::HashSet<_>>();
+    let removed = old.iter().filter(|value| !new_values.contains(value));
+    let added = new.iter().filter(|value| !old_values.contains(value));
+    removed
+        .map(|value| ('-', value.clone()))
+        .chain(added.map(|value| ('+', value.clone())))
+        .collect()
+}
+
+/// A representative value of a leaf's option, if one can be made up: its
+/// default, or a value of its type.
+fn example_value(node: &SchemaNode, options: &GenerateOptions) -> Option<String> {
+    if options
+        .type_overrides
+        .contains_key(&node.path(SchemaPathFormat::DATA))
+    {
+        return None;
+    }
+    if let Optionality::Default(default) = leaf_optionality(node) {
+        return Some(default);
+    }
+    let value = match node.base_type()? {
+        DataValueType::Int8
+        | DataValueType::Int16
+        | DataValueType::Int32
+        | DataValueType::Uint8
+        | DataValueType::Uint16
+        | DataValueType::Uint32 => "1",
+        DataValueType::Int64 | DataValueType::Uint64 | DataValueType::Dec64 => "\"1\"",
+        DataValueType::Bool => "true",
+        DataValueType::String | DataValueType::Enum | DataValueType::Union => "\"example\"",
+        DataValueType::InstanceId => "\"/example\"",
+        _ => return None,
+    };
+    Some(value.to_string())
+}
+
+/// An assignment of an example value to the first leaf below `node` that one
+/// can be made up for, with `path` the attribute path of `node`'s parent.
+/// Keyed lists get an entry named by example key values, keyless lists are
+/// skipped.
+fn example_setting(
+    node: &SchemaNode,
+    path: &mut Vec<String>,
+    options: &GenerateOptions,
+) -> Option<String> {
+    let levels = match node.kind() {
+        SchemaNodeKind::Leaf | SchemaNodeKind::LeafList if !node.is_list_key() => {
+            let value = example_value(node, options)?;
+            let value = match node.kind() {
+                SchemaNodeKind::LeafList => format!("[ {} ]", value),
+                _ => value,
+            };
+            let mut attrs = path.clone();
+            attrs.push(nix_attr_name(node.name(), false));
+            return Some(format!("{} = {};", attrs.join("."), value));
+        }
+        SchemaNodeKind::Container | SchemaNodeKind::Choice | SchemaNodeKind::Case => vec![],
+        SchemaNodeKind::List if !node.is_keyless_list() => node
+            .list_keys()
+            .map(|key| {
+                example_value(&key, options).map_or("example".to_string(), |value| {
+                    value.trim_matches('"').to_string()
+                })
+            })
+            .map(|name| nix_attr_name(&name, false))
+            .collect(),
+        _ => return None,
+    };
+    let depth = path.len();
+    if !(node.kind() == SchemaNodeKind::Case && is_shorthand_case(node)) {
+        path.push(nix_attr_name(node.name(), false));
+    }
+    path.extend(levels);
+    let setting = node
+        .children()
+        .find_map(|child| example_setting(&child, path, options));
+    path.truncate(depth);
+    setting
+}
+
+/// A NixOS VM test importing the generated options from `options.nix` and
+/// setting an example value below each schema root, which checks that the
+/// options evaluate.
+fn nixos_test<'a>(
+    roots: impl Iterator<Item = SchemaNode<'a>>,
+    options: &GenerateOptions,
+) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Generated by nix-yang-tools, next to the options written with"
+    )
+    .unwrap();
+    writeln!(out, "# `nix_options > options.nix`.").unwrap();
+    writeln!(out, "{{ pkgs, ... }}:").unwrap();
+    writeln!(out, "pkgs.testers.nixosTest {{").unwrap();
+    writeln!(out, "  name = \"yang-options\";").unwrap();
+    writeln!(out, "  nodes.machine = {{ lib, ... }}: {{").unwrap();
+    writeln!(
+        out,
+        "    options.yang = import ./options.nix {{ inherit lib; }};"
+    )
+    .unwrap();
+    writeln!(out, "    config = {{").unwrap();
+    for root in roots {
+        let mut path = vec!["yang".to_string()];
+        match example_setting(&root, &mut path, options) {
+            Some(setting) => writeln!(out, "      {}", setting).unwrap(),
+            None => writeln!(out, "      # no example value for {}", root.name()).unwrap(),
+        }
+    }
+    writeln!(out, "    }};").unwrap();
+    writeln!(out, "  }};").unwrap();
+    writeln!(out, "  testScript = ''").unwrap();
+    writeln!(out, "    machine.wait_for_unit(\"multi-user.target\")").unwrap();
+    writeln!(out, "  '';").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// An HCL string literal.
+fn hcl_string(s: &str) -> String {
+    // JSON escapes are HCL escapes, plus the template sequences
+    json_string(&serde_json::Value::from(s), false)
+        .replace("${", "$${")
+        .replace("%{", "%%{")
+}
+
+/// An HCL attribute name, quoted unless it is an identifier.
+fn hcl_attr_name(name: &str) -> String {
+    match name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-".contains(c))
+    {
+        true if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => name.to_string(),
+        _ => hcl_string(name),
+    }
+}
+
+/// Terraform type of the values of a leaf or leaf-list, by the JSON type
+/// yang2nix gives them.
+fn terraform_scalar_type(node: &SchemaNode) -> &'static str {
+    match nix_json_type(node) {
+        Some("number") => "number",
+        Some("boolean") => "bool",
+        Some(_) => "string",
+        None => "any",
+    }
+}
+
+/// HCL literal of the default value of a leaf, if it has one.
+fn terraform_default(node: &SchemaNode) -> Option<String> {
+    let default = node.default_value_canonical()?;
+    Some(match terraform_scalar_type(node) {
+        "number" | "bool" => default.to_string(),
+        _ => hcl_string(default),
+    })
+}
+
+/// Terraform type of the values of a schema node, in the shape yang2nix
+/// gives them: keyed lists are maps nested once per key, and choices and
+/// cases are flattened. Lines are continued at `indent`.
+fn terraform_type(node: &SchemaNode, indent: &str) -> String {
+    let object = |children: Vec<SchemaNode>| {
+        let inner = format!("{}  ", indent);
+        let mut out = String::from("object({\n");
+        for child in children {
+            let child_type = terraform_type(&child, &inner);
+            let name = hcl_attr_name(child.name());
+            let attr_type = match child.kind() {
+                SchemaNodeKind::Leaf if child.is_mandatory() => child_type,
+                SchemaNodeKind::Leaf => match terraform_default(&child) {
+                    Some(default) => format!("optional({}, {})", child_type, default),
+                    None => format!("optional({})", child_type),
+                },
+                // so that the defaults below them apply
+                SchemaNodeKind::Container => format!("optional({}, {{}})", child_type),
+                _ => format!("optional({})", child_type),
+            };
+            writeln!(out, "{}{} = {}", inner, name, attr_type).unwrap();
+        }
+        write!(out, "{}}})", indent).unwrap();
+        out
+    };
+    match node.kind() {
+        SchemaNodeKind::Container => object(data_children(node.children())),
+        SchemaNodeKind::List if node.is_keyless_list() => {
+            format!("list({})", object(data_children(node.children())))
+        }
+        SchemaNodeKind::List => {
+            // the key values name the entries instead
+            let children = data_children(node.children());
+            let entry = object(children.into_iter().filter(|c| !c.is_list_key()).collect());
+            node.list_keys()
+                .fold(entry, |entry_type, _| format!("map({})", entry_type))
+        }
+        SchemaNodeKind::Leaf => terraform_scalar_type(node).to_string(),
+        SchemaNodeKind::LeafList => format!("list({})", terraform_scalar_type(node)),
+        _ => "any".to_string(),
+    }
+}
+
+/// Terraform `variable` blocks for the schema roots, typed like the
+/// documents yang2nix produces.
+fn terraform_variables<'a>(
+    roots: impl Iterator<Item = SchemaNode<'a>>,
+    options: &GenerateOptions,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Generated by nix-yang-tools").unwrap();
+    for root in data_children(roots) {
+        writeln!(out).unwrap();
+        writeln!(out, "variable {} {{", hcl_string(root.name())).unwrap();
+        let description = description_lines(&root, options);
+        if !description.is_empty() {
+            let description = hcl_string(&description.join("\n"));
+            writeln!(out, "  description = {}", description).unwrap();
+        }
+        writeln!(out, "  type = {}", terraform_type(&root, "  ")).unwrap();
+        let default = match root.kind() {
+            SchemaNodeKind::Container => Some("{}".to_string()),
+            SchemaNodeKind::Leaf => terraform_default(&root),
+            _ => None,
+        };
+        writeln!(out, "  default = {}", default.as_deref().unwrap_or("null")).unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+/// Write the options of each schema root into its own file, along with a
+/// `default.nix` merging all of them.
+fn write_nix_options_dir<'a>(
+    dir: &Path,
+    roots: impl Iterator<Item = SchemaNode<'a>>,
+    options: &GenerateOptions,
+    readme: &mut Option<Readme>,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut default = String::from("{ lib, ... }:\n");
+    for (i, root) in roots.enumerate() {
+        let file = format!("{}.nix", root.name());
+        write_atomic(
+            &dir.join(&file),
+            nix_options_file(std::iter::once(root), options, readme),
+        )?;
+        if i > 0 {
+            default += "//\n";
+        }
+        writeln!(default, "(import ./{} {{ inherit lib; }})", file).unwrap();
+    }
+    write_atomic(&dir.join("default.nix"), default)
+}
+
+/// Write a file by writing a temporary file next to it and renaming that
+/// into place, so that a failed or interrupted write leaves the previous
+/// version of the file alone rather than a truncated one.
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_atomic_with(path, |file| file.write_all(contents.as_ref()))
+}
+
+/// Write a file atomically like `write_atomic`, with `write` writing its
+/// contents. The file is only replaced if `write` succeeds.
+fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut dyn std::io::Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let located = |err: std::io::Error| {
+        std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+    };
+    if path.is_dir() {
+        return Err(located(std::io::Error::other("is a directory")));
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // temporary files are only readable by their owner
+    let permissions = match std::fs::metadata(path) {
+        Ok(meta) => meta.permissions(),
+        Err(_) => std::fs::Permissions::from_mode(0o644),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir).map_err(located)?;
+    write(&mut file).map_err(located)?;
+    file.as_file()
+        .set_permissions(permissions)
+        .map_err(located)?;
+    file.as_file().sync_all().map_err(located)?;
+    file.persist(path).map_err(|err| located(err.error))?;
+    Ok(())
+}
+
+/// Something the converter did to a document, for `--report`.
+struct ReportItem {
+    path: String,
+    action: &'static str,
+    message: Option<String>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+impl ReportItem {
+    fn new(path: &str, action: &'static str) -> ReportItem {
+        ReportItem {
+            path: path.to_string(),
+            action,
+            message: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    fn message(mut self, message: impl Into<String>) -> ReportItem {
+        self.message = Some(message.into());
+        self
+    }
+
+    fn before(mut self, value: &serde_json::Value) -> ReportItem {
+        self.before = Some(value.clone());
+        self
+    }
+
+    fn after(mut self, value: &serde_json::Value) -> ReportItem {
+        self.after = Some(value.clone());
+        self
+    }
+
+    /// The JSON of a value, capped to keep reports of large lists small.
+    ///
+    /// Serialization stops once there is enough for the snippet, so large
+    /// values aren't serialized in full only to be cut.
+    fn snippet(value: &serde_json::Value) -> String {
+        const MAX: usize = 200;
+
+        /// Takes bytes until it holds `MAX` characters' worth and more.
+        struct Capped(Vec<u8>);
+
+        impl std::io::Write for Capped {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0.len() > 4 * MAX {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut capped = Capped(vec![]);
+        // only fails once capped
+        let _ = serde_json::to_writer(&mut capped, value);
+        let json = String::from_utf8_lossy(&capped.0);
+        match json.char_indices().nth(MAX) {
+            Some((end, _)) => format!("{}…", &json[..end]),
+            None => json.into_owned(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path,
+            "action": self.action,
+            "message": self.message,
+            "before": self.before.as_ref().map(Self::snippet),
+            "after": self.after.as_ref().map(Self::snippet),
+        })
+    }
+}
+
+/// The items of a `--report`, collected only if a report was requested.
+#[derive(Default)]
+struct Report {
+    items: Option<Vec<ReportItem>>,
+}
+
+impl Report {
+    fn new(enabled: bool) -> Report {
+        Report {
+            items: enabled.then(Vec::new),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.items.is_some()
+    }
+
+    /// Add the item built by `item`, which is only called if reporting.
+    fn push(&mut self, item: impl FnOnce() -> ReportItem) {
+        if let Some(items) = &mut self.items {
+            items.push(item());
+        }
+    }
+
+    fn items(&self) -> &[ReportItem] {
+        self.items.as_deref().unwrap_or_default()
+    }
+}
+
+/// The modules of a context as `name@revision`, sorted, and a fingerprint
+/// of them.
+fn schema_fingerprint(ctx: &Context) -> (Vec<String>, String) {
+    let mut modules = ctx
+        .modules(true)
+        .map(|module| format!("{}@{}", module.name(), module.revision().unwrap_or("none")))
+        .collect::<Vec<_>>();
+    modules.sort();
+    // FNV-1a, to be stable across builds
+    let fingerprint = modules
+        .join("\n")
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    (modules, format!("{:016x}", fingerprint))
+}
+
+/// SHA-256 digest of some data, in hex.
+fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (hh, g, f, e) = (g, f, e, d.wrapping_add(t1));
+            (d, c, b, a) = (c, b, a, t1.wrapping_add(t2));
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+/// The current time as an RFC 3339 UTC timestamp.
+fn utc_timestamp() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    rfc3339_utc(now)
+}
+
+/// A time in seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn rfc3339_utc(time: i64) -> String {
+    let (days, secs) = (time.div_euclid(86400), time.rem_euclid(86400));
+    // civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Comment lines recording where a Nix rendition of a document came from:
+/// the input and its digest, the tool and schema, the time unless it is
+/// left out for reproducible output, and how many values each top-level
+/// subtree holds.
+fn provenance_comment(
+    ctx: &Context,
+    input: &Path,
+    data: &serde_json::Value,
+    timestamp: bool,
+) -> String {
+    fn values(value: &serde_json::Value) -> usize {
+        match value {
+            serde_json::Value::Object(object) => object.values().map(values).sum(),
+            serde_json::Value::Array(array) => array.iter().map(values).sum(),
+            _ => 1,
+        }
+    }
+
+    let digest = std::fs::read(input).map_or_else(|err| err.to_string(), |d| sha256_hex(&d));
+    let (modules, fingerprint) = schema_fingerprint(ctx);
+    let mut out = String::new();
+    writeln!(out, "# Generated from {}", input.display()).unwrap();
+    writeln!(out, "# sha256: {}", digest).unwrap();
+    writeln!(
+        out,
+        "# by {} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(out, "# schema: {} ({} modules)", fingerprint, modules.len()).unwrap();
+    if timestamp {
+        writeln!(out, "# at {}", utc_timestamp()).unwrap();
+    }
+    for (name, value) in data.as_object().into_iter().flatten() {
+        writeln!(out, "#   {}: {} values", name, values(value)).unwrap();
+    }
+    out
+}
+
+/// A JSON report of a conversion run: what was done to each file, and the
+/// errors that made it fail.
+fn conversion_report(
+    ctx: &Context,
+    results: &[(PathBuf, Vec<ConvertError>)],
+    reports: &[Report],
+) -> serde_json::Value {
+    let (modules, fingerprint) = schema_fingerprint(ctx);
+
+    let files = results
+        .iter()
+        .zip(reports)
+        .map(|((input, errors), report)| {
+            let items = report.items();
+            let mut counters = BTreeMap::<&str, usize>::new();
+            for item in items {
+                *counters.entry(item.action).or_default() += 1;
+            }
+            serde_json::json!({
+                "file": input.display().to_string(),
+                "errors": errors.iter().map(|error| serde_json::json!({
+                    "path": error.path,
+                    "message": error.message,
+                })).collect::<Vec<_>>(),
+                "counters": counters,
+                "items": items.iter().map(ReportItem::to_json).collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "tool": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "schema": {
+            "modules": modules,
+            "fingerprint": fingerprint,
+        },
+        "files": files,
+    })
+}
+
+/// An error encountered while converting a document, located by the JSON
+/// pointer (or, for validation errors, the data path) of the offending value.
+struct ConvertError {
+    path: String,
+    message: String,
+}
+
+impl ConvertError {
+    fn new(path: &str, message: impl Into<String>) -> ConvertError {
+        ConvertError {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::fmt::Debug for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+/// The ways the tool can fail, for the functions that return their errors
+/// rather than reporting them.
+enum NixYangError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Yang(yang2::Error),
+    Conversion(ConvertError),
+    /// The schema couldn't be set up, e.g. a module wasn't found.
+    Schema(String),
+}
+
+impl std::fmt::Display for NixYangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixYangError::Io(err) => write!(f, "{}", err),
+            NixYangError::Json(err) => write!(f, "{}", err),
+            NixYangError::Yang(err) => write!(f, "{}", err),
+            NixYangError::Conversion(err) => write!(f, "{}", err),
+            NixYangError::Schema(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// main prints the Debug form of the error it returns
+impl std::fmt::Debug for NixYangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for NixYangError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NixYangError::Io(err) => Some(err),
+            NixYangError::Json(err) => Some(err),
+            NixYangError::Yang(err) => Some(err),
+            NixYangError::Conversion(err) => Some(err),
+            NixYangError::Schema(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for NixYangError {
+    fn from(err: std::io::Error) -> NixYangError {
+        NixYangError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for NixYangError {
+    fn from(err: serde_json::Error) -> NixYangError {
+        NixYangError::Json(err)
+    }
+}
+
+impl From<yang2::Error> for NixYangError {
+    fn from(err: yang2::Error) -> NixYangError {
+        NixYangError::Yang(err)
+    }
+}
+
+impl From<ConvertError> for NixYangError {
+    fn from(err: ConvertError) -> NixYangError {
+        NixYangError::Conversion(err)
+    }
+}
+
+/// Append a member name or array index to a JSON pointer.
+fn json_pointer_push(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Reduce a document to the subtree at a JSON pointer, keeping the members
+/// and array entries leading to it so that it stays a complete document.
+fn prune_to_pointer(mut value: serde_json::Value, pointer: &str) -> Option<serde_json::Value> {
+    if pointer.is_empty() {
+        return Some(value);
+    }
+    let tokens = pointer.strip_prefix('/')?;
+    let (token, rest) = match tokens.find('/') {
+        Some(i) => (&tokens[..i], &tokens[i..]),
+        None => (tokens, ""),
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match &mut value {
+        serde_json::Value::Object(object) => {
+            let child = prune_to_pointer(object.remove(&token)?, rest)?;
+            Some(serde_json::json!({ token: child }))
+        }
+        serde_json::Value::Array(array) => {
+            let i = token.parse::<usize>().ok().filter(|i| *i < array.len())?;
+            let child = prune_to_pointer(array.swap_remove(i), rest)?;
+            Some(serde_json::Value::Array(vec![child]))
+        }
+        _ => None,
+    }
+}
+
+/// Collect all JSON files below a directory, in a stable order.
+fn json_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            json_files(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "json") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Extract a `.tar`, `.tar.gz`/`.tgz` or `.zip` archive of YANG modules into
+/// a temporary directory, which is removed when dropped.
+fn extract_schema_archive(archive: &Path) -> std::io::Result<tempfile::TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix("nix-yang-tools-schemas")
+        .tempdir()?;
+    unpack_schema_archive(archive, dir.path())?;
+    Ok(dir)
+}
+
+/// Extract a `.tar`, `.tar.gz`/`.tgz` or `.zip` archive of YANG modules into
+/// a directory.
+fn unpack_schema_archive(archive: &Path, dir: &Path) -> std::io::Result<()> {
+    let file = BufReader::new(File::open(archive)?);
+    let name = archive.to_string_lossy();
+    if name.ends_with(".zip") {
+        zip::ZipArchive::new(file)
+            .and_then(|mut zip| zip.extract(dir))
+            .map_err(std::io::Error::other)?;
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        tar::Archive::new(flate2::bufread::GzDecoder::new(file)).unpack(dir)?;
+    } else if name.ends_with(".tar") {
+        tar::Archive::new(file).unpack(dir)?;
+    } else {
+        return Err(std::io::Error::other(
+            "unknown archive type, expected .tar, .tar.gz, .tgz or .zip",
+        ));
+    }
+    Ok(())
+}
+
+/// Modification time of a file, as a string to compare.
+fn mtime(path: &Path) -> Option<String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    let since = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(format!("{}.{:09}", since.as_secs(), since.subsec_nanos()))
+}
+
+/// The YANG files in a directory with their modification times.
+fn yang_files(dir: &Path) -> std::io::Result<serde_json::Map<String, serde_json::Value>> {
+    let mut files = serde_json::Map::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "yang" || ext == "yin")
+        {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            files.insert(name, mtime(&path).into());
+        }
+    }
+    Ok(files)
+}
+
+/// What the modules of a context are loaded from, which a cached list of
+/// their directories is only valid for.
+fn context_cache_key(
+    search_dirs: &[PathBuf],
+    archives: &[PathBuf],
+    imports: &[String],
+    recursive: bool,
+) -> serde_json::Value {
+    let archives = archives.iter().map(
+        |archive| serde_json::json!({ "path": archive.to_string_lossy(), "mtime": mtime(archive) }),
+    );
+    serde_json::json!({
+        "search_dirs": search_dirs.iter().map(|dir| dir.to_string_lossy()).collect::<Vec<_>>(),
+        "archives": archives.collect::<Vec<_>>(),
+        "imports": imports,
+        "recursive": recursive,
+    })
+}
+
+/// The directories the modules of a context were loaded from on an earlier
+/// run with the same cache key, if none of the YANG files in them changed
+/// since.
+fn cached_module_dirs(cache_dir: &Path, key: &serde_json::Value) -> Option<Vec<PathBuf>> {
+    let text = std::fs::read_to_string(cache_dir.join("context.json")).ok()?;
+    let cache: serde_json::Value = serde_json::from_str(&text).ok()?;
+    if cache["key"] != *key {
+        return None;
+    }
+    let mut dirs = vec![];
+    for (dir, files) in cache["dirs"].as_object()? {
+        let dir = PathBuf::from(dir);
+        if yang_files(&dir).ok()? != *files.as_object()? {
+            return None;
+        }
+        dirs.push(dir);
+    }
+    Some(dirs)
+}
+
+/// Record the directories the modules of a context were loaded from, with
+/// the YANG files in them.
+fn write_context_cache(
+    cache_dir: &Path,
+    key: &serde_json::Value,
+    ctx: &Context,
+) -> std::io::Result<()> {
+    let mut dirs = serde_json::Map::new();
+    for module in ctx.modules(true) {
+        let Some(dir) = module.filepath().and_then(|path| Path::new(path).parent()) else {
+            continue;
+        };
+        let name = dir.to_string_lossy().to_string();
+        if !dirs.contains_key(&name) {
+            dirs.insert(name, yang_files(dir)?.into());
+        }
+    }
+    let cache = serde_json::json!({ "key": key, "dirs": dirs });
+    std::fs::create_dir_all(cache_dir)?;
+    write_atomic(&cache_dir.join("context.json"), cache.to_string())
+}
+
+/// Collect all directories below a directory.
+fn subdirectories(dir: &Path, dirs: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?.path();
+        if entry.is_dir() {
+            dirs.push(entry.clone());
+            subdirectories(&entry, dirs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the module qualifiers of all member names, between module names
+/// and module prefixes. Qualifiers of modules not in the context, or with a
+/// prefix shared by several modules, are left as they are.
+fn requalify(value: &mut serde_json::Value, ctx: &Context, to_prefix: bool) {
+    match value {
+        serde_json::Value::Object(object) => {
+            let members = std::mem::take(object);
+            for (key, mut member) in members {
+                requalify(&mut member, ctx, to_prefix);
+                let key = match key.split_once(':') {
+                    Some((qualifier, name)) => {
+                        let mut modules = ctx.modules(true).filter(|module| {
+                            qualifier
+                                == if to_prefix {
+                                    module.name()
+                                } else {
+                                    module.prefix()
+                                }
+                        });
+                        match (modules.next(), modules.next()) {
+                            (Some(module), None) if to_prefix => {
+                                format!("{}:{}", module.prefix(), name)
+                            }
+                            (Some(module), None) => format!("{}:{}", module.name(), name),
+                            _ => key,
+                        }
+                    }
+                    None => key,
+                };
+                object.insert(key, member);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for member in array {
+                requalify(member, ctx, to_prefix);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collect the JSON pointers of members qualified with a module that isn't
+/// in the context, by module name, removing them if `remove` is set.
+fn unknown_modules(
+    value: &mut serde_json::Value,
+    path: &str,
+    ctx: &Context,
+    remove: bool,
+    found: &mut BTreeMap<String, Vec<String>>,
+) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|key, member| {
+                let path = json_pointer_push(path, key);
+                match key.split_once(':') {
+                    Some((module, _)) if ctx.get_module_latest(module).is_none() => {
+                        found.entry(module.to_string()).or_default().push(path);
+                        !remove
+                    }
+                    _ => {
+                        unknown_modules(member, &path, ctx, remove, found);
+                        true
+                    }
+                }
+            });
+        }
+        serde_json::Value::Array(array) => {
+            for (i, member) in array.iter_mut().enumerate() {
+                let path = json_pointer_push(path, &i.to_string());
+                unknown_modules(member, &path, ctx, remove, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Report top-level members of a YANG-style document that aren't data of any
+/// loaded module.
+fn reject_unknown_members(
+    data: &serde_json::Value,
+    top_level: &[SchemaNode],
+    errors: &mut Vec<ConvertError>,
+) {
+    for key in data
+        .as_object()
+        .into_iter()
+        .flat_map(|object| object.keys())
+    {
+        let known = key.split_once(':').is_some_and(|(module, name)| {
+            top_level
+                .iter()
+                .any(|root| root.module().name() == module && root.name() == name)
+        });
+        if !known {
+            errors.push(ConvertError::new(
+                &json_pointer_push("", key),
+                "not a top-level node of any loaded module",
+            ));
+        }
+    }
+}
+
+/// Validate a YANG-style document against the loaded schema.
+fn validate(
+    ctx: &Arc<Context>,
+    doc: &YangDoc,
+    options: &ConvertOptions,
+    errors: &mut Vec<ConvertError>,
+) {
+    let (parser_flags, validation_flags) = options.parse.flags(true, true);
+    if let Err(err) = DataTree::parse_string(
+        ctx,
+        &doc.0.to_string(),
+        DataFormat::JSON,
+        parser_flags,
+        validation_flags,
+    ) {
+        let path = err.path.as_deref().unwrap_or_default();
+        // missing mandatory nodes the account can't see are expected
+        if let Some(visible) = &options.visible_paths {
+            static LOCATION: LazyLock<regex::Regex> =
+                LazyLock::new(|| regex::Regex::new(r#"^Data location "([^"]+)""#).unwrap());
+            static MANDATORY: LazyLock<regex::Regex> =
+                LazyLock::new(|| regex::Regex::new(r#"^Mandatory node "([^"]+)""#).unwrap());
+            let mut node = LOCATION
+                .captures(path)
+                .map_or(path, |captures| captures.get(1).unwrap().as_str())
+                .to_string();
+            if let Some(captures) = MANDATORY.captures(&err.to_string()) {
+                node = format!("{}/{}", node.trim_end_matches('/'), &captures[1]);
+            }
+            if visible.visibility(&node) == Visibility::Hidden {
+                return;
+            }
+        }
+        errors.push(ConvertError::new(path, err.to_string()));
+    }
+}
+
+/// Where a data node stands with respect to the paths an account can see.
+#[derive(Debug, PartialEq)]
+enum Visibility {
+    /// At or below a visible path.
+    Visible,
+    /// Above a visible path only, as one of the nodes leading to it.
+    Ancestor,
+    Hidden,
+}
+
+/// A node of a data path: its name, the module qualifying it if any, and
+/// its predicates as name and value pairs.
+#[derive(Debug, PartialEq)]
+struct PathSegment {
+    module: Option<String>,
+    name: String,
+    predicates: Vec<(String, String)>,
+}
+
+impl PathSegment {
+    /// Whether a segment of a path prefix matches a segment of a data
+    /// path. Module qualifiers only need to agree if both have one, and
+    /// the data path may have predicates the prefix doesn't, so that a list
+    /// without predicates in a prefix stands for all of its entries.
+    fn matches(&self, other: &PathSegment) -> bool {
+        self.name == other.name
+            && (self.module.is_none() || other.module.is_none() || self.module == other.module)
+            && self
+                .predicates
+                .iter()
+                .all(|predicate| other.predicates.contains(predicate))
+    }
+}
+
+/// Split a data path such as `/m:a/b[name='x']/c` into its segments.
+/// Predicate values may be quoted either way; `/` and `]` inside them don't
+/// end anything.
+fn parse_data_path(path: &str) -> Vec<PathSegment> {
+    let mut segments = vec![];
+    let mut chars = path.trim().trim_start_matches('/').chars().peekable();
+    while chars.peek().is_some() {
+        let mut qualified = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '/' || c == '[' {
+                break;
+            }
+            qualified.push(c);
+            chars.next();
+        }
+        let mut predicates = vec![];
+        while chars.peek() == Some(&'[') {
+            chars.next();
+            let (mut predicate, mut quote) = (String::new(), None);
+            for c in chars.by_ref() {
+                match quote {
+                    Some(q) if c == q => quote = None,
+                    None if c == '\'' || c == '"' => quote = Some(c),
+                    None if c == ']' => break,
+                    _ => {}
+                }
+                predicate.push(c);
+            }
+            let (key, value) = predicate.split_once('=').unwrap_or(("", &predicate));
+            let value = value.trim();
+            let unquoted = value
+                .strip_prefix(['\'', '"'])
+                .and_then(|value| value.strip_suffix(['\'', '"']))
+                .unwrap_or(value);
+            predicates.push((key.trim().to_string(), unquoted.to_string()));
+        }
+        if chars.peek() == Some(&'/') {
+            chars.next();
+        }
+        let (module, name) = match qualified.split_once(':') {
+            Some((module, name)) => (Some(module.to_string()), name.to_string()),
+            None => (None, qualified),
+        };
+        segments.push(PathSegment {
+            module,
+            name,
+            predicates,
+        });
+    }
+    segments
+}
+
+/// Data path prefixes an account can see, e.g. through NACM rules, read from
+/// a file with one path per line and `#` comments.
+struct VisiblePaths(Vec<Vec<PathSegment>>);
+
+impl VisiblePaths {
+    fn read(path: &Path) -> VisiblePaths {
+        let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path.display(), err);
+            std::process::exit(1);
+        });
+        let prefixes = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(parse_data_path)
+            .collect();
+        VisiblePaths(prefixes)
+    }
+
+    fn visibility(&self, path: &str) -> Visibility {
+        let path = parse_data_path(path);
+        let mut ancestor = false;
+        for prefix in &self.0 {
+            let common = prefix
+                .iter()
+                .zip(&path)
+                .take_while(|(prefix, segment)| prefix.matches(segment))
+                .count();
+            if common == prefix.len() {
+                return Visibility::Visible;
+            }
+            ancestor |= common == path.len();
+        }
+        match ancestor {
+            true => Visibility::Ancestor,
+            false => Visibility::Hidden,
+        }
+    }
+}
+
+/// Remove the data an account can't see from a data tree, keeping the
+/// nodes leading to what it can see.
+fn restrict_to_visible(dtree: &mut DataTree, visible: &VisiblePaths) -> Result<(), yang2::Error> {
+    use yang2::iter::NodeIterable;
+
+    let hidden = dtree
+        .traverse()
+        // list keys are part of the entries leading to visible nodes
+        .filter(|dnode| !dnode.schema().is_list_key())
+        .filter(|dnode| visible.visibility(&dnode.path()) == Visibility::Hidden)
+        .filter(|dnode| {
+            dnode
+                .parent()
+                .is_none_or(|parent| visible.visibility(&parent.path()) != Visibility::Hidden)
+        })
+        .map(|dnode| dnode.path())
+        .collect::<Vec<_>>();
+    for path in hidden {
+        dtree.remove(&path)?;
+    }
+    Ok(())
+}
+
+/// Read the JSON documents of an input file, or of the subtree at
+/// `--json-pointer` of each.
+fn read_documents(
+    path: &Path,
+    options: &ConvertOptions,
+) -> Result<Vec<serde_json::Value>, Vec<ConvertError>> {
+    let mut data = String::new();
+    File::open(path)
+        .and_then(|file| BufReader::new(file).read_to_string(&mut data))
+        .map_err(|err| vec![ConvertError::new("", err.to_string())])?;
+    if data.trim().is_empty() {
+        return Err(vec![ConvertError::new("", "input file is empty")]);
+    }
+    let documents = parse_json_documents(&data, options.ndjson).map_err(|err| vec![err])?;
+    let Some(pointer) = &options.json_pointer else {
+        return Ok(documents);
+    };
+
+    let mut pruned = vec![];
+    let mut errors = vec![];
+    for (i, data) in documents.into_iter().enumerate() {
+        match prune_to_pointer(data, pointer) {
+            Some(data) => pruned.push(data),
+            None => errors.push(document_error(
+                i,
+                options,
+                ConvertError::new(pointer, "no value at this JSON pointer"),
+            )),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(pruned),
+        false => Err(errors),
+    }
+}
+
+/// An error about the i-th document of an input, which for a stream of
+/// documents is located as if it were an array.
+fn document_error(i: usize, options: &ConvertOptions, mut error: ConvertError) -> ConvertError {
+    if options.ndjson {
+        error.path = json_pointer_push("", &i.to_string()) + &error.path;
+    }
+    error
+}
+
+/// Read the documents of an input file, in the representation `mode`
+/// converts from or, without it, the one detected from the first document.
+/// The documents of a stream are all in the same representation.
+fn read_input(
+    ctx: &Context,
+    path: &Path,
+    mode: Option<&ConvertMode>,
+    options: &ConvertOptions,
+) -> Result<Vec<Doc>, Vec<ConvertError>> {
+    let mut documents = read_documents(path, options)?.into_iter();
+    let mut docs = vec![];
+    let mode = match mode {
+        Some(mode) => mode.clone(),
+        None => {
+            let top_level = data_children(
+                ctx.modules(true)
+                    .filter(|module| module.is_implemented())
+                    .flat_map(|module| module.data()),
+            );
+            let first = documents.next().unwrap_or_default();
+            let doc = Doc::detect(first, &top_level).map_err(|_| {
+                let message = "can't tell whether the input is YANG-style or Nix-style, \
+                               use --yang2nix or --nix2yang";
+                vec![ConvertError::new("", message)]
+            })?;
+            let mode = doc.mode();
+            docs.push(doc);
+            mode
+        }
+    };
+    docs.extend(documents.map(|document| Doc::new(document, &mode)));
+    Ok(docs)
+}
+
+/// Convert the documents of an input file.
+///
+/// Problems are collected into `errors` rather than aborting, so that those
+/// of all documents can be reported at once.
+fn convert_docs(
+    ctx: &Arc<Context>,
+    path: &Path,
+    docs: Vec<Doc>,
+    options: &ConvertOptions,
+    errors: &mut Vec<ConvertError>,
+    report: &mut Report,
+) -> Vec<Doc> {
+    let mut converted = vec![];
+    for (i, doc) in docs.into_iter().enumerate() {
+        match doc.convert(ctx, path, options, report) {
+            Ok(doc) => converted.push(doc),
+            Err(document_errors) => errors.extend(
+                document_errors
+                    .into_iter()
+                    .map(|error| document_error(i, options, error)),
+            ),
+        }
+    }
+    converted
+}
+
+/// Parse the JSON document of an input file, or with `ndjson` the sequence of
+/// documents it holds. Syntax errors are located by line and column and
+/// quote the input around them, as inputs are often too large to look for
+/// the position by hand.
+fn parse_json_documents(text: &str, ndjson: bool) -> Result<Vec<serde_json::Value>, ConvertError> {
+    // the input around a 1-based line and column
+    let near = |line: usize, column: usize| {
+        let line = text.lines().nth(line.saturating_sub(1)).unwrap_or_default();
+        let (mut start, mut end) = (column.saturating_sub(30), column + 30);
+        while start > 0 && !line.is_char_boundary(start) {
+            start -= 1;
+        }
+        end = end.min(line.len());
+        while !line.is_char_boundary(end) {
+            end += 1;
+        }
+        format!("{:?}", &line[start.min(end)..end])
+    };
+
+    let mut documents = vec![];
+    let mut stream = serde_json::Deserializer::from_str(text).into_iter();
+    while let Some(document) = stream.next() {
+        match document {
+            Ok(document) => documents.push(document),
+            Err(err) => {
+                let hint = match err.is_eof() {
+                    true => ", the input looks truncated",
+                    false => "",
+                };
+                return Err(ConvertError::new(
+                    "",
+                    format!("{}, near {}{}", err, near(err.line(), err.column()), hint),
+                ));
+            }
+        }
+        let rest = &text[stream.byte_offset()..];
+        if !ndjson && !rest.trim().is_empty() {
+            let offset = text.len() - rest.trim_start().len();
+            let line = text[..offset].matches('\n').count() + 1;
+            let column = offset - text[..offset].rfind('\n').map_or(0, |i| i + 1) + 1;
+            return Err(ConvertError::new(
+                "",
+                format!(
+                    "trailing data after the JSON document at line {} column {}, near {}; \
+                     to convert concatenated documents one by one, use --ndjson",
+                    line,
+                    column,
+                    near(line, column)
+                ),
+            ));
+        }
+    }
+    Ok(documents)
+}
+
+/// Whether a parsed document can be converted at all: it must be an object
+/// and, with `--max-total-nodes`, not too large to handle.
+fn convertible(
+    data: &serde_json::Value,
+    options: &ConvertOptions,
+    errors: &mut Vec<ConvertError>,
+) -> bool {
+    let kind = match data {
+        serde_json::Value::Object(_) => None,
+        serde_json::Value::Array(_) => Some("an array"),
+        serde_json::Value::String(_) => Some("a string"),
+        serde_json::Value::Number(_) => Some("a number"),
+        serde_json::Value::Bool(_) => Some("a boolean"),
+        serde_json::Value::Null => Some("null"),
+    };
+    if let Some(kind) = kind {
+        let path = options.json_pointer.as_deref().unwrap_or("");
+        errors.push(ConvertError::new(
+            path,
+            format!(
+                "expected an object with members of the loaded modules, found {}",
+                kind
+            ),
+        ));
+        return false;
+    }
+    if let Some(max) = options.max_total_nodes {
+        if exceeds_nodes(data, max) {
+            let message = format!("document has more than --max-total-nodes {} values", max);
+            errors.push(ConvertError::new("", message));
+            return false;
+        }
+    }
+    true
+}
+
+/// The implemented modules of a context, as a document may hold the data of
+/// any of them, with their top-level data nodes.
+fn implemented_modules(ctx: &Context) -> (Vec<SchemaModule<'_>>, Vec<SchemaNode<'_>>) {
+    let modules = ctx
+        .modules(true)
+        .filter(|module| module.is_implemented())
+        .collect::<Vec<_>>();
+    let top_level = data_children(modules.iter().flat_map(|module| module.data()));
+    (modules, top_level)
+}
+
+/// Unwrap the RESTCONF or NETCONF envelope around the actual data of a
+/// document, returning its key to rewrap the converted document with.
+fn unwrap_envelope(
+    path: &Path,
+    data: &mut serde_json::Value,
+    modules: &[SchemaModule],
+    top_level: &[SchemaNode],
+    options: &ConvertOptions,
+    report: &mut Report,
+) -> Option<String> {
+    let envelope = match data.as_object_mut() {
+        Some(object) if object.len() == 1 => {
+            let (key, payload) = object.iter_mut().next().unwrap();
+            let unwrap = key == "ietf-restconf:data" || options.unwrap.contains(key);
+            if unwrap && payload.is_object() {
+                Some((key.clone(), payload.take()))
+            } else {
+                let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+                let known = top_level
+                    .iter()
+                    .any(|root| root.name() == name || options.nix_name(root.name()) == name)
+                    || (options.namespace_by_module
+                        && modules.iter().any(|module| module.name() == key));
+                if !known && payload.is_object() {
+                    let message = format!(
+                        "not a schema node, if it is an envelope try --unwrap {}",
+                        key
+                    );
+                    let pointer = json_pointer_push("", key);
+                    eprintln!("warning: {}: {}: {}", path.display(), pointer, message);
+                    report.push(|| ReportItem::new(&pointer, "warning").message(message));
+                }
+                None
+            }
+        }
+        _ => None,
+    };
+    envelope.map(|(key, payload)| {
+        *data = payload;
+        report.push(|| ReportItem::new(&json_pointer_push("", &key), "unwrap"));
+        key
+    })
+}
+
+/// Rewrap a converted document in the envelope it was unwrapped from.
+fn rewrap(data: &mut serde_json::Value, envelope: Option<String>, options: &ConvertOptions) {
+    if let (Some(key), false) = (envelope, options.no_rewrap) {
+        *data = serde_json::json!({ key: data.take() });
+    }
+}
+
+/// Keep only the top-level members given with `--only`, if any.
+fn retain_only(data: &mut serde_json::Value, options: &ConvertOptions, report: &mut Report) {
+    if let (false, Some(object)) = (options.only.is_empty(), data.as_object_mut()) {
+        object.retain(|key, _| {
+            let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+            let keep = options
+                .only
+                .iter()
+                .any(|only| only == key || only == name || options.nix_name(only) == name);
+            if !keep {
+                report.push(|| ReportItem::new(&json_pointer_push("", key), "skip"));
+            }
+            keep
+        });
+    }
+}
+
+/// Move the members of a Nix-style document nested under module names by
+/// `--namespace-by-module` up to the top level, qualified with the module
+/// name. Members that aren't named after a loaded module are left alone.
+fn unnest_modules(
+    data: &mut serde_json::Value,
+    modules: &[SchemaModule],
+    errors: &mut Vec<ConvertError>,
+) {
+    let object = match data.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+    // the members outside of modules go first, whatever the order of keys
+    let mut nested = vec![];
+    for (key, member) in std::mem::take(object) {
+        match member {
+            serde_json::Value::Object(members)
+                if modules.iter().any(|module| module.name() == key) =>
+            {
+                nested.push((key, members))
+            }
+            member => drop(object.insert(key, member)),
+        }
+    }
+    for (key, members) in nested {
+        for (name, member) in members {
+            let qualified = match name.split_once(':') {
+                Some(_) => name.clone(),
+                None => format!("{}:{}", key, name),
+            };
+            if object.contains_key(&qualified) {
+                let path = json_pointer_push(&json_pointer_push("", &key), &name);
+                errors.push(ConvertError::new(&path, "also given outside of the module"));
+                continue;
+            }
+            object.insert(qualified, member);
+        }
+    }
+}
+
+/// Nest the top-level members of a converted Nix-style document under the
+/// names of their modules, dropping any module prefixes they kept.
+fn nest_modules(data: &mut serde_json::Value, top_level: &[SchemaNode], options: &ConvertOptions) {
+    let object = match data.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+    for (key, member) in std::mem::take(object) {
+        let (module, name) = match key.split_once(':') {
+            Some((module, name)) => (Some(module.to_string()), name.to_string()),
+            None => {
+                let module = top_level
+                    .iter()
+                    .find(|root| options.nix_name(root.name()) == key)
+                    .map(|root| root.module().name().to_string());
+                (module, key)
+            }
+        };
+        match module {
+            Some(module) => {
+                let nested = object
+                    .entry(module)
+                    .or_insert_with(|| serde_json::Value::Object(Default::default()));
+                if let Some(nested) = nested.as_object_mut() {
+                    nested.insert(name, member);
+                }
+            }
+            None => {
+                object.insert(name, member);
+            }
+        }
+    }
+}
+
+/// Warn about lists of a YANG-style object with more than `max` entries.
+fn warn_large_lists(
+    value: &serde_json::Value,
+    path: &str,
+    children: &[SchemaNode],
+    max: usize,
+    report: &mut Report,
+) {
+    for (key, member) in value.as_object().into_iter().flatten() {
+        let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+        let Some(node) = children.iter().find(|child| child.name() == name) else {
+            continue;
+        };
+        let member_path = json_pointer_push(path, key);
+        match (node.kind(), member) {
+            (SchemaNodeKind::List, serde_json::Value::Array(entries)) => {
+                if entries.len() > max {
+                    let message = format!(
+                        "list has {} entries, more than --max-list-size {}",
+                        entries.len(),
+                        max
+                    );
+                    eprintln!("warning: {}: {}", member_path, message);
+                    report.push(|| ReportItem::new(&member_path, "warning").message(message));
+                }
+                let children = data_children(node.children());
+                for (i, entry) in entries.iter().enumerate() {
+                    let entry_path = json_pointer_push(&member_path, &i.to_string());
+                    warn_large_lists(entry, &entry_path, &children, max, report);
+                }
+            }
+            (SchemaNodeKind::Container, _) => {
+                let children = data_children(node.children());
+                warn_large_lists(member, &member_path, &children, max, report);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a document has more than `max` JSON values, counting no further
+/// than that.
+fn exceeds_nodes(value: &serde_json::Value, max: usize) -> bool {
+    fn count(value: &serde_json::Value, remaining: &mut usize) -> bool {
+        if *remaining == 0 {
+            return true;
+        }
+        *remaining -= 1;
+        match value {
+            serde_json::Value::Object(object) => object.values().any(|v| count(v, remaining)),
+            serde_json::Value::Array(array) => array.iter().any(|v| count(v, remaining)),
+            _ => false,
+        }
+    }
+    let mut remaining = max;
+    count(value, &mut remaining)
+}
+
+/// Fail on the first list of a document with more than `max` entries, in
+/// the Nix representation with `nix` and the YANG one otherwise. Returns
+/// whether one was found.
+fn exceeds_list_size(
+    value: &serde_json::Value,
+    path: &str,
+    children: &[SchemaNode],
+    nix: bool,
+    max: usize,
+    errors: &mut Vec<ConvertError>,
+) -> bool {
+    // the entries of a keyed list nested once per key, with their pointers
+    fn nested<'v>(
+        value: &'v serde_json::Value,
+        path: &str,
+        levels: usize,
+        out: &mut Vec<(String, &'v serde_json::Value)>,
+    ) {
+        match (levels, value) {
+            (0, _) => out.push((path.to_string(), value)),
+            (_, serde_json::Value::Object(object)) => {
+                for (key, value) in object {
+                    nested(value, &json_pointer_push(path, key), levels - 1, out);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn count_nested(value: &serde_json::Value, levels: usize) -> usize {
+        match (levels, value) {
+            (0, _) => 1,
+            (_, serde_json::Value::Object(object)) => {
+                object.values().map(|v| count_nested(v, levels - 1)).sum()
+            }
+            _ => 0,
+        }
+    }
+
+    for (key, member) in value.as_object().into_iter().flatten() {
+        let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+        let Some(node) = children.iter().find(|child| child.name() == name) else {
+            continue;
+        };
+        let member_path = json_pointer_push(path, key);
+        let children = data_children(node.children());
+        let levels = node.list_keys().count();
+        // counted before the entries are collected, which is what the limit
+        // is there to prevent for lists too large to handle
+        let count = match (node.kind(), member) {
+            (SchemaNodeKind::List, serde_json::Value::Array(entries)) => entries.len(),
+            (SchemaNodeKind::List, _) if nix && !node.is_keyless_list() => {
+                count_nested(member, levels)
+            }
+            (SchemaNodeKind::Container, _) => {
+                if exceeds_list_size(member, &member_path, &children, nix, max, errors) {
+                    return true;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if count > max {
+            errors.push(ConvertError::new(
+                &member_path,
+                format!(
+                    "list has {} entries, more than --max-entries-per-list {}",
+                    count, max
+                ),
+            ));
+            return true;
+        }
+        let entries = match member {
+            serde_json::Value::Array(entries) => entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| (json_pointer_push(&member_path, &i.to_string()), entry))
+                .collect(),
+            _ => {
+                let mut entries = vec![];
+                nested(member, &member_path, levels, &mut entries);
+                entries
+            }
+        };
+        for (entry_path, entry) in entries {
+            if exceeds_list_size(entry, &entry_path, &children, nix, max, errors) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Remove the members of objects that are null, or empty objects or arrays
+/// once their own empty members are removed. Array elements are kept, as
+/// `[null]` is the value of an `empty` leaf.
+fn prune_empty(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => object.retain(|_, member| {
+            prune_empty(member);
+            match member {
+                serde_json::Value::Null => false,
+                serde_json::Value::Object(object) => !object.is_empty(),
+                serde_json::Value::Array(array) => !array.is_empty(),
+                _ => true,
+            }
+        }),
+        serde_json::Value::Array(array) => array.iter_mut().for_each(prune_empty),
+        _ => {}
+    }
+}
+
+/// Convert a YANG document to the Nix representation, with `path` naming its
+/// source in warnings.
+fn yang_to_nix(
+    ctx: &Arc<Context>,
+    path: &Path,
+    mut doc: YangDoc,
+    options: &ConvertOptions,
+    report: &mut Report,
+) -> Result<NixDoc, Vec<ConvertError>> {
+    let mut errors = vec![];
+    if !convertible(&doc.0, options, &mut errors) {
+        return Err(errors);
+    }
+    check_string_chars(&mut doc.0, "", &options.invalid_chars, &mut errors, report);
+    let (modules, top_level) = implemented_modules(ctx);
+    let envelope = unwrap_envelope(path, &mut doc.0, &modules, &top_level, options, report);
+    retain_only(&mut doc.0, options, report);
+
+    if !options.origins.is_empty() {
+        filter_origin(&mut doc.0, None, &options.origins);
+    }
+    if let JsonNamespace::Prefix = options.json_namespace {
+        requalify(&mut doc.0, ctx, false);
+    }
+    if options.reject_unknown {
+        reject_unknown_members(&doc.0, &top_level, &mut errors);
+    }
+    if options.validate {
+        validate(ctx, &doc, options, &mut errors);
+    }
+    if let Some(max) = options.max_list_size {
+        warn_large_lists(&doc.0, "", &top_level, max, report);
+    }
+    if let Some(max) = options.max_entries_per_list {
+        if exceeds_list_size(&doc.0, "", &top_level, false, max, &mut errors) {
+            return Err(errors);
+        }
+    }
+
+    let mut data = doc.0;
+    let mode = ConvertMode::Yang2Nix;
+    convert(&mut data, &modules, &mode, options, &mut errors, report);
+    if options.renames() {
+        rename_members(&mut data, "", None, &top_level, options, true, &mut errors);
+    }
+    if options.prune_empty {
+        prune_empty(&mut data);
+    }
+    if options.namespace_by_module {
+        nest_modules(&mut data, &top_level, options);
+    }
+    if let (Some(prefix), Some(object)) = (&options.key_prefix, data.as_object_mut()) {
+        for (key, member) in std::mem::take(object) {
+            object.insert(format!("{}{}", prefix, key), member);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    rewrap(&mut data, envelope, options);
+    Ok(NixDoc(data))
+}
+
+/// Convert a Nix document to the YANG representation, with `path` naming its
+/// source in warnings.
+fn nix_to_yang(
+    ctx: &Arc<Context>,
+    path: &Path,
+    doc: NixDoc,
+    options: &ConvertOptions,
+    report: &mut Report,
+) -> Result<YangDoc, Vec<ConvertError>> {
+    let mut errors = vec![];
+    let mut data = doc.0;
+    if !convertible(&data, options, &mut errors) {
+        return Err(errors);
+    }
+    check_string_chars(&mut data, "", &options.invalid_chars, &mut errors, report);
+    let (modules, top_level) = implemented_modules(ctx);
+    let envelope = unwrap_envelope(path, &mut data, &modules, &top_level, options, report);
+    if let (Some(prefix), Some(object)) = (&options.key_prefix, data.as_object_mut()) {
+        for (key, member) in std::mem::take(object) {
+            let key = key
+                .strip_prefix(prefix.as_str())
+                .map(String::from)
+                .unwrap_or(key);
+            object.insert(key, member);
+        }
+    }
+    if options.namespace_by_module {
+        unnest_modules(&mut data, &modules, &mut errors);
+    }
+    retain_only(&mut data, options, report);
+
+    // check the whole document up front, so that all missing modules are
+    // reported at once
+    let mut found = BTreeMap::new();
+    let skip = options.skip_unknown_modules;
+    unknown_modules(&mut data, "", ctx, skip, &mut found);
+    for (module, paths) in found {
+        if skip {
+            for pointer in &paths {
+                report.push(|| {
+                    ReportItem::new(pointer, "skip")
+                        .message(format!("module {} is not loaded", module))
+                });
+            }
+            eprintln!(
+                "warning: {}: skipped {} subtrees of unloaded module {}: {}",
+                path.display(),
+                paths.len(),
+                module,
+                paths.join(", ")
+            );
+        } else {
+            errors.push(ConvertError::new(
+                &paths[0],
+                format!(
+                    "module {} is not loaded (used by {} members), add it with --import",
+                    module,
+                    paths.len()
+                ),
+            ));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // always run, as it also restores module prefixes
+    rename_members(&mut data, "", None, &top_level, options, false, &mut errors);
+    if let Some(max) = options.max_entries_per_list {
+        if exceeds_list_size(&data, "", &top_level, true, max, &mut errors) {
+            return Err(errors);
+        }
+    }
+    if options.reject_unknown {
+        reject_unknown_members(&data, &top_level, &mut errors);
+    }
+    if options.type_assertion {
+        let mut type_errors = vec![];
+        assert_types(&mut data, "", &top_level, &mut type_errors);
+        if options.ignore_type_errors {
+            for error in type_errors {
+                eprintln!("warning: {}: {}", path.display(), error);
+            }
+        } else if !type_errors.is_empty() {
+            errors.extend(type_errors);
+            return Err(errors);
+        }
+    }
+    // 64-bit values are always restored to strings, as Nix can't represent
+    // all of them as integers
+    coerce_members(
+        &mut data,
+        "",
+        &top_level,
+        !options.coerce,
+        &mut errors,
+        report,
+    );
+    let mode = ConvertMode::Nix2Yang;
+    convert(&mut data, &modules, &mode, options, &mut errors, report);
+
+    let mut doc = YangDoc(data);
+    if options.validate && errors.is_empty() {
+        validate(ctx, &doc, options, &mut errors);
+    }
+    if let JsonNamespace::Prefix = options.json_namespace {
+        requalify(&mut doc.0, ctx, true);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    rewrap(&mut doc.0, envelope, options);
+    Ok(doc)
+}
+
+/// Convert the keyed lists of a document between their YANG representation
+/// (arrays of entries) and their Nix representation (attrsets nested once per
+/// key).
+fn convert(
+    data: &mut serde_json::Value,
+    modules: &[SchemaModule],
+    mode: &ConvertMode,
+    options: &ConvertOptions,
+    errors: &mut Vec<ConvertError>,
+    report: &mut Report,
+) {
+    for node in modules
+        .iter()
+        .flat_map(|module| module.data())
+        .flat_map(|root| root.traverse().collect::<Vec<_>>().into_iter().rev())
+        // only lists that have keys
+        .filter(|node| node.kind() == SchemaNodeKind::List && !node.is_keyless_list())
+        .filter(|node| {
+            options.schema_path.as_deref().is_none_or(|prefix| {
+                let path = node.path(SchemaPathFormat::DATA);
+                path.strip_prefix(prefix.trim_end_matches('/'))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+        })
+    {
+        let mut p = vec![(String::new(), &mut *data)];
+
+        let mut ancestors = node
+            .inclusive_ancestors()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .enumerate();
+        let ancestors_len = ancestors.len();
+
+        for (i, an) in &mut ancestors {
+            let k = if i == 0 {
+                format!("{}:{}", an.module().name(), an.name())
+            } else {
+                an.name().to_string()
+            };
+
+            p = p
+                .into_iter()
+                .flat_map(|(path, x)| {
+                    let path = json_pointer_push(&path, &k);
+                    x.get_mut(&k).map(|x| (path, x))
+                })
+                .collect();
+
+            // last ancestor
+            if i == (ancestors_len - 1) {
+                // last node ; convert
+                let keys = match options.key_order(&node) {
+                    Ok(keys) => keys,
+                    Err(err) => {
+                        let path = p.first().map_or("", |(path, _)| path.as_str());
+                        errors.push(ConvertError::new(path, err));
+                        break;
+                    }
+                };
+                for (path, e) in &mut p {
+                    let before = report.is_enabled().then(|| e.clone());
+                    match mode {
+                        ConvertMode::Yang2Nix => {
+                            if let Some(field) = &options.schema_path_field {
+                                let schema_path = node.path(SchemaPathFormat::DATA);
+                                for entry in e.as_array_mut().into_iter().flatten() {
+                                    if let Some(entry) = entry.as_object_mut() {
+                                        entry.insert(field.clone(), schema_path.clone().into());
+                                    }
+                                }
+                            }
+                            yang_list_to_nix(e, path, &keys, options, errors, report)
+                        }
+                        ConvertMode::Nix2Yang => {
+                            nix_list_to_yang(e, path, &keys, options.normalize_keys, errors)
+                        }
+                    }
+                    if let Some(before) = before {
+                        report.push(|| {
+                            ReportItem::new(path, "reshape-list")
+                                .before(&before)
+                                .after(e)
+                        });
+                    }
+                }
+                break;
+            }
+            if an.kind() == SchemaNodeKind::List {
+                // Lists are converted innermost first, so enclosing lists are
+                // still in the input representation: one array level for YANG
+                // and keyless lists, one attrset level per key for Nix.
+                let levels = match mode {
+                    _ if an.is_keyless_list() => 1,
+                    ConvertMode::Yang2Nix => 1,
+                    ConvertMode::Nix2Yang => an.list_keys().count(),
+                };
+                for _ in 0..levels {
+                    p = p
+                        .into_iter()
+                        .flat_map(
+                            |(path, x)| -> Box<dyn Iterator<Item = (String, &mut serde_json::Value)>> {
+                                match x {
+                                    serde_json::Value::Array(a) => {
+                                        Box::new(a.iter_mut().enumerate().map(move |(i, x)| {
+                                            (json_pointer_push(&path, &i.to_string()), x)
+                                        }))
+                                    }
+                                    serde_json::Value::Object(o) => {
+                                        Box::new(o.iter_mut().map(move |(k, x)| {
+                                            (json_pointer_push(&path, k), x)
+                                        }))
+                                    }
+                                    _ => {
+                                        errors.push(ConvertError::new(
+                                            &path,
+                                            "expected an array or object",
+                                        ));
+                                        Box::new(std::iter::empty())
+                                    }
+                                }
+                            },
+                        )
+                        .collect();
+                }
+            }
+
+            if p.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Turn the entries of a keyed list into attribute sets nested once per key,
+/// in the order of `keys`.
+fn yang_list_to_nix(
+    e: &mut serde_json::Value,
+    path: &str,
+    keys: &[SchemaNode],
+    options: &ConvertOptions,
+    errors: &mut Vec<ConvertError>,
+    report: &mut Report,
+) {
+    let deduplicate = &options.deduplicate;
+    // the key values before normalization, by the normalized ones
+    let mut normalized = std::collections::HashMap::new();
+    // the index of the first entry with each key
+    let mut first_entry = std::collections::HashMap::new();
+    let as_array = match e.take() {
+        serde_json::Value::Array(a) => a,
+        other => {
+            *e = other;
+            errors.push(ConvertError::new(
+                path,
+                "expected an array. Are you sure this is a YANG-style file?",
+            ));
+            return;
+        }
+    };
+    *e = serde_json::Value::Object(Default::default());
+
+    'elements: for (i, mut el) in as_array.into_iter().enumerate() {
+        let el_path = json_pointer_push(path, &i.to_string());
+        let object = match el.as_object_mut() {
+            Some(object) => object,
+            None => {
+                errors.push(ConvertError::new(&el_path, "expected an object"));
+                continue;
+            }
+        };
+
+        let mut key_values = vec![];
+        let mut raw_values = vec![];
+        for key_node in keys {
+            // Attribute names are always strings, so numeric and boolean keys
+            // are stringified here and restored by their type in nix2yang.
+            let key = if options.keep_keys {
+                object.get(key_node.name()).cloned()
+            } else {
+                object.remove(key_node.name())
+            };
+            let key = match key {
+                Some(serde_json::Value::String(s)) => {
+                    raw_values.push(s.clone());
+                    key_values.push(options.normalize_keys.apply(&s));
+                    continue;
+                }
+                Some(serde_json::Value::Number(n)) => n.to_string(),
+                Some(serde_json::Value::Bool(b)) => b.to_string(),
+                Some(other) => {
+                    errors.push(ConvertError::new(
+                        &json_pointer_push(&el_path, key_node.name()),
+                        format!("can not use {} as a key", other),
+                    ));
+                    continue 'elements;
+                }
+                None => {
+                    errors.push(ConvertError::new(
+                        &el_path,
+                        format!("missing key {:?}", key_node.name()),
+                    ));
+                    continue 'elements;
+                }
+            };
+            raw_values.push(key.clone());
+            key_values.push(key);
+        }
+        if options.normalize_keys != NormalizeKeys::None {
+            let raw = normalized
+                .entry(key_values.clone())
+                .or_insert_with(|| raw_values.clone());
+            if *raw != raw_values {
+                errors.push(ConvertError::new(
+                    &el_path,
+                    format!(
+                        "keys {:?} are the same as {:?} after --normalize-keys",
+                        raw_values, raw
+                    ),
+                ));
+                continue;
+            }
+        }
+
+        let first = *first_entry.entry(key_values.clone()).or_insert(i);
+
+        let mut p2 = &mut *e; // reference to the value where the element will be inserted
+        for k in key_values {
+            if !p2.is_object() {
+                *p2 = serde_json::Value::Object(Default::default());
+            };
+            p2 = p2
+                .as_object_mut()
+                .unwrap()
+                .entry(k)
+                .or_insert(serde_json::Value::Null);
+        }
+        if !p2.is_null() {
+            match deduplicate {
+                Some(Deduplicate::Last) => {
+                    let message = format!(
+                        "duplicate key {:?} of entry {}, replacing the earlier entry",
+                        raw_values, first
+                    );
+                    eprintln!("warning: {}: {}", el_path, message);
+                    report.push(|| ReportItem::new(&el_path, "warning").message(message));
+                }
+                Some(Deduplicate::Error) => {
+                    errors.push(ConvertError::new(
+                        &el_path,
+                        format!("duplicate key {:?} of entry {}", raw_values, first),
+                    ));
+                    continue;
+                }
+                None => {}
+            }
+        }
+        *p2 = el; // insert element
+    }
+}
+
+/// Turn the nested attribute sets of a keyed list back into its entries,
+/// nesting level i holding the values of `keys[i]`.
+fn nix_list_to_yang(
+    e: &mut serde_json::Value,
+    path: &str,
+    keys: &[SchemaNode],
+    normalize_keys: NormalizeKeys,
+    errors: &mut Vec<ConvertError>,
+) {
+    let key_count = keys.len();
+    let mut a = vec![];
+    // the attribute names before normalization, by the normalized ones
+    let mut normalized = std::collections::HashMap::new();
+
+    let mut q: Vec<(Vec<String>, String, _)> = vec![(vec![], path.to_string(), e.take())];
+
+    'entries: while let Some((depth, path, mut el)) = q.pop() {
+        if depth.len() == key_count {
+            let object = match el.as_object_mut() {
+                Some(object) => object,
+                None => {
+                    errors.push(ConvertError::new(&path, "expected an object"));
+                    continue;
+                }
+            };
+            // numeric and boolean keys are left alone
+            let depth = match normalize_keys {
+                NormalizeKeys::None => depth,
+                _ => {
+                    let normalized_keys = depth
+                        .iter()
+                        .zip(keys)
+                        .map(|(key, key_node)| match nix_json_type(key_node) {
+                            Some("string") => normalize_keys.apply(key),
+                            _ => key.clone(),
+                        })
+                        .collect::<Vec<_>>();
+                    let raw = normalized
+                        .entry(normalized_keys.clone())
+                        .or_insert_with(|| depth.clone());
+                    if *raw != depth {
+                        errors.push(ConvertError::new(
+                            &path,
+                            format!(
+                                "keys {:?} are the same as {:?} after --normalize-keys",
+                                depth, raw
+                            ),
+                        ));
+                        continue;
+                    }
+                    normalized_keys
+                }
+            };
+            for (level, (key, key_node)) in depth.into_iter().zip(keys).enumerate() {
+                let mut key = serde_json::Value::from(key);
+                if let Err(err) = coerce_value(&mut key, key_node) {
+                    let order = keys.iter().map(|key| key.name()).collect::<Vec<_>>();
+                    errors.push(ConvertError::new(
+                        &path,
+                        format!(
+                            "nesting level {} holds key {:?}: {}; levels are keys {}, \
+                             use --key-order for files nesting them differently",
+                            level + 1,
+                            key_node.name(),
+                            err,
+                            order.join(",")
+                        ),
+                    ));
+                    continue 'entries;
+                }
+                // a key leaf kept in the entry, e.g. by --keep-keys, has to
+                // name the same entry
+                if let Some(inner) = object.get(key_node.name()) {
+                    let mut inner = inner.clone();
+                    if let (Some("string"), serde_json::Value::String(s)) =
+                        (nix_json_type(key_node), &mut inner)
+                    {
+                        *s = normalize_keys.apply(s);
+                    }
+                    let _ = coerce_value(&mut inner, key_node);
+                    if inner != key {
+                        errors.push(ConvertError::new(
+                            &json_pointer_push(&path, key_node.name()),
+                            format!(
+                                "key {:?} is {} but the entry is named {}",
+                                key_node.name(),
+                                object[key_node.name()],
+                                key
+                            ),
+                        ));
+                        continue 'entries;
+                    }
+                }
+                object.insert(key_node.name().to_string(), key);
+            }
+            a.push(el);
+        } else {
+            let as_object = if let serde_json::Value::Object(o) = el.take() {
+                o
+            } else {
+                errors.push(ConvertError::new(
+                    &path,
+                    "expected an object. Are you sure this is a Nix-style file?",
+                ));
+                continue;
+            };
+            for (key, el2) in as_object {
+                let path = json_pointer_push(&path, &key);
+                let mut depth = depth.clone();
+                depth.push(key);
+                q.push((depth, path, el2));
+            }
+        }
+    }
+
+    *e = serde_json::Value::Array(a);
+}
+
+/// The members of `right` that differ from `left`, to be deep-merged into
+/// it. Attribute sets can't express removal, so the attribute paths of
+/// members only `left` has are collected in `removals`. Lists that aren't
+/// attribute sets are replaced as a whole.
+fn nix_overlay(
+    left: &NixDoc,
+    right: &NixDoc,
+    removals: &mut Vec<Vec<String>>,
+) -> Option<serde_json::Value> {
+    nix_value_overlay(&left.0, &right.0, &[], removals)
+}
+
+/// The overlay of the values at `path` of two documents, see
+/// [`nix_overlay`].
+fn nix_value_overlay(
+    left: &serde_json::Value,
+    right: &serde_json::Value,
+    path: &[String],
+    removals: &mut Vec<Vec<String>>,
+) -> Option<serde_json::Value> {
+    use serde_json::Value;
+
+    match (left, right) {
+        (Value::Object(left), Value::Object(right)) => {
+            let key_path = |key: &str| {
+                let mut key_path = path.to_vec();
+                key_path.push(key.to_string());
+                key_path
+            };
+            for key in left.keys().filter(|key| !right.contains_key(*key)) {
+                removals.push(key_path(key));
+            }
+            let mut overlay = serde_json::Map::new();
+            for (key, value) in right {
+                let changed = match left.get(key) {
+                    Some(left) => nix_value_overlay(left, value, &key_path(key), removals),
+                    None => Some(value.clone()),
+                };
+                if let Some(changed) = changed {
+                    overlay.insert(key.clone(), changed);
+                }
+            }
+            (!overlay.is_empty()).then_some(Value::Object(overlay))
+        }
+        (left, right) if left == right => None,
+        _ => Some(right.clone()),
+    }
+}
+
+/// The JSON pointer of the first member or element that differs between two
+/// documents.
+fn first_difference(a: &serde_json::Value, b: &serde_json::Value, path: &str) -> Option<String> {
+    use serde_json::Value;
+
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let added = b.keys().filter(|key| !a.contains_key(*key));
+            a.keys().chain(added).find_map(|key| {
+                let path = json_pointer_push(path, key);
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => first_difference(a, b, &path),
+                    _ => Some(path),
+                }
+            })
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            a.iter().zip(b).enumerate().find_map(|(i, (a, b))| {
+                first_difference(a, b, &json_pointer_push(path, &i.to_string()))
+            })
+        }
+        (a, b) if a == b => None,
+        _ => Some(path.to_string()),
+    }
+}
+
+/// Round-trip the defaults of the loaded schema through yang2nix and
+/// nix2yang, and validate the result. Returns the number of data nodes in
+/// the tree of defaults, or the first problem found.
+fn self_test(ctx: &Arc<Context>, options: &ConvertOptions) -> Result<usize, NixYangError> {
+    use yang2::data::{Data, DataImplicitFlags, DataPrinterFlags};
+
+    let mut dtree = DataTree::new(ctx);
+    dtree.add_implicit(DataImplicitFlags::NO_STATE)?;
+    let json = dtree
+        .print_string(
+            DataFormat::JSON,
+            DataPrinterFlags::WITH_SIBLINGS | DataPrinterFlags::WD_ALL,
+        )?
+        .unwrap_or_else(|| "{}".to_string());
+    let defaults: YangDoc = serde_json::from_str(&json)?;
+
+    let source = Path::new("(self-test)");
+    let mut report = Report::default();
+    let errors = yang_to_nix(ctx, source, defaults.clone(), options, &mut report)
+        .and_then(|nix| nix_to_yang(ctx, source, nix, options, &mut report))
+        .map_or_else(
+            |errors| errors,
+            |yang| {
+                let mut errors = vec![];
+                validate(ctx, &yang, options, &mut errors);
+                if let (true, Some(path)) = (
+                    errors.is_empty(),
+                    first_difference(&defaults.0, &yang.0, ""),
+                ) {
+                    errors.push(ConvertError::new(&path, "changed by the round trip"));
+                }
+                errors
+            },
+        );
+    match errors.into_iter().next() {
+        Some(error) => Err(error.into()),
+        None => Ok(dtree.traverse().count()),
+    }
+}
+
+/// Description of a schema node for diff annotations, including its units.
+///
+/// Only the first line of the description is used unless `full` is set.
+fn diff_description(node: &SchemaNode, full: bool) -> Option<String> {
+    let description = node.description().map(|description| {
+        if full {
+            description.trim().to_string()
+        } else {
+            description
+                .trim()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+    });
+    match (description, node.units()) {
+        (Some(description), Some(units)) => Some(format!("{} (units: {})", description, units)),
+        (Some(description), None) => Some(description),
+        (None, Some(units)) => Some(format!("units: {}", units)),
+        (None, None) => None,
+    }
+}
+
+/// Options of the grep mode.
+#[derive(Default)]
+struct GrepOptions {
+    names_only: bool,
+    values_only: bool,
+    limit: Option<usize>,
+}
+
+/// Lines for the schema nodes whose name or description match, and the data
+/// nodes whose value matches:
+///
+/// ```text
+/// schema<TAB><path><TAB><kind><TAB><type>
+/// data<TAB><path><TAB><value>
+/// ```
+fn grep(
+    ctx: &Context,
+    pattern: &regex::Regex,
+    data: Option<&DataTree>,
+    options: &GrepOptions,
+) -> Vec<String> {
+    let mut lines = vec![];
+    if !options.values_only {
+        let nodes = ctx
+            .modules(true)
+            .filter(|module| module.is_implemented())
+            .flat_map(|module| {
+                module
+                    .data()
+                    .flat_map(|root| root.traverse())
+                    .collect::<Vec<_>>()
+            });
+        for node in nodes {
+            let matches = pattern.is_match(node.name())
+                || !options.names_only
+                    && node
+                        .description()
+                        .is_some_and(|description| pattern.is_match(description));
+            if matches {
+                let base_type = node.base_type().map(|t| format!("{:?}", t));
+                lines.push(format!(
+                    "schema\t{}\t{}\t{}",
+                    node.path(SchemaPathFormat::DATA),
+                    format!("{:?}", node.kind()).to_lowercase(),
+                    base_type.as_deref().unwrap_or("-")
+                ));
+            }
+        }
+    }
+    if let Some(data) = data.filter(|_| !options.names_only) {
+        for dnode in data.traverse() {
+            if let Some(value) = dnode.value_canonical().filter(|v| pattern.is_match(v)) {
+                lines.push(format!("data\t{}\t{}", dnode.path(), value));
+            }
+        }
+    }
+    lines.truncate(options.limit.unwrap_or(usize::MAX));
+    lines
+}
+
+/// Keep only the nodes of an operational datastore document whose origin is
+/// one of `origins`, returning whether anything was kept.
+///
+/// Origins are read from `ietf-origin:origin` annotations (RFC 7952) and
+/// inherited by the nodes below, as described in RFC 8342. Nodes without an
+/// origin are dropped. Annotations are removed from the document, as the
+/// origin module usually isn't loaded.
+fn filter_origin(value: &mut serde_json::Value, origin: Option<&str>, origins: &[String]) -> bool {
+    fn annotated_origin(annotation: Option<&serde_json::Value>) -> Option<&str> {
+        let origin = annotation?.get("ietf-origin:origin")?.as_str()?;
+        // identities are qualified with their module
+        Some(origin.split_once(':').map_or(origin, |(_, name)| name))
+    }
+
+    let object = match value {
+        serde_json::Value::Object(object) => object,
+        _ => return origin.is_some_and(|origin| origins.iter().any(|o| o == origin)),
+    };
+    let origin = annotated_origin(object.get("@"))
+        .or(origin)
+        .map(str::to_string);
+    let annotations = object
+        .iter()
+        .filter(|(key, _)| key.starts_with('@'))
+        .map(|(key, value)| (key[1..].to_string(), value.clone()))
+        .collect::<BTreeMap<_, _>>();
+    object.retain(|key, _| !key.starts_with('@'));
+
+    let members = object.len();
+    object.retain(|key, member| {
+        let annotation = annotations.get(key);
+        match member {
+            serde_json::Value::Array(entries) => {
+                let mut i = 0;
+                entries.retain_mut(|entry| {
+                    // leaf-list entries are annotated by index
+                    let entry_origin = annotated_origin(annotation.and_then(|a| a.get(i)));
+                    i += 1;
+                    filter_origin(entry, entry_origin.or(origin.as_deref()), origins)
+                });
+                !entries.is_empty()
+            }
+            _ => {
+                let member_origin = annotated_origin(annotation);
+                filter_origin(member, member_origin.or(origin.as_deref()), origins)
+            }
+        }
+    });
+    !object.is_empty() || members == 0 && origin.is_some_and(|origin| origins.contains(&origin))
+}
+
+/// Parse a JSON data file for diffing.
+///
+/// Empty files are rejected, or read as an empty tree with `empty_as_tree`.
+fn parse_data_file(
+    ctx: &Arc<Context>,
+    path: &Path,
+    empty_as_tree: bool,
+    origins: &[String],
+    parse: &ParseOptions,
+) -> DataTree {
+    let data = read_data_json(path, empty_as_tree, origins);
+    // libyang locates errors by line number, which only refer to the file
+    // if libyang parses it as it is
+    let text = match origins.is_empty() {
+        true => std::fs::read_to_string(path).ok(),
+        false => None,
+    };
+    match text.filter(|text| !text.trim().is_empty()) {
+        Some(text) => parse_data_string(ctx, path, &text, parse),
+        None => parse_data_string(ctx, path, &data.0.to_string(), parse),
+    }
+}
+
+/// A libyang error with the location and app-tag libyang gives for it, which
+/// its Display leaves out.
+fn libyang_error(err: &yang2::Error) -> String {
+    let mut message = err.to_string();
+    if let Some(path) = &err.path {
+        write!(message, " ({})", path.trim_end_matches('.')).unwrap();
+    }
+    if let Some(apptag) = &err.apptag {
+        write!(message, " [error-app-tag {}]", apptag).unwrap();
+    }
+    message
+}
+
+/// Read the JSON document of a data file, keeping only the nodes of the
+/// given origins if there are any.
+fn read_data_json(path: &Path, empty_as_tree: bool, origins: &[String]) -> YangDoc {
+    let data = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    if data.trim().is_empty() {
+        if empty_as_tree {
+            return YangDoc(serde_json::json!({}));
+        }
+        eprintln!("{}: input file is empty", path.display());
+        std::process::exit(1);
+    }
+    // libyang's errors don't locate syntax errors
+    let mut value = match parse_json_documents(&data, false) {
+        Ok(mut documents) => documents.pop().unwrap_or_default(),
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    if !origins.is_empty() {
+        filter_origin(&mut value, None, origins);
+    }
+    YangDoc(value)
+}
+
+/// Parse a JSON document as a data tree, exiting with an error about `path`
+/// if it isn't one.
+fn parse_data_string(
+    ctx: &Arc<Context>,
+    path: &Path,
+    data: &str,
+    parse: &ParseOptions,
+) -> DataTree {
+    let (parser_flags, validation_flags) = parse.flags(false, false);
+    DataTree::parse_string(ctx, data, DataFormat::JSON, parser_flags, validation_flags)
+        .unwrap_or_else(|err| {
+            eprintln!("{}: {}", path.display(), libyang_error(&err));
+            std::process::exit(1);
+        })
+}
+
+/// Fill in the wildcards of a template document from a document compared
+/// against it, so that they don't show up as differences. Leaves and
+/// leaf-list entries equal to `wildcard` take the value of the other
+/// document, and list entries with wildcard keys take the keys of an entry
+/// of the other document not matched otherwise. The data paths of filled in
+/// nodes are collected in `instantiated`.
+fn instantiate_template(
+    template: &mut serde_json::Value,
+    other: &serde_json::Value,
+    children: &[SchemaNode],
+    wildcard: &str,
+    path: &str,
+    instantiated: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    let (Some(template), Some(other)) = (template.as_object_mut(), other.as_object()) else {
+        return;
+    };
+    for (member, value) in template.iter_mut() {
+        let name = member
+            .split_once(':')
+            .map_or(member.as_str(), |(_, name)| name);
+        let (Some(node), Some(other)) = (
+            children.iter().find(|child| child.name() == name),
+            other.get(member),
+        ) else {
+            continue;
+        };
+        let path = format!("{}/{}", path, member);
+        match node.kind() {
+            SchemaNodeKind::Leaf if value.as_str() == Some(wildcard) => {
+                *value = other.clone();
+                instantiated.push(path);
+            }
+            SchemaNodeKind::LeafList => {
+                let (Some(entries), Some(others)) = (value.as_array_mut(), other.as_array()) else {
+                    continue;
+                };
+                let unmatched = others.iter().filter(|other| !entries.contains(other));
+                let mut unmatched = unmatched.cloned().collect::<Vec<_>>().into_iter();
+                for entry in entries.iter_mut() {
+                    if entry.as_str() == Some(wildcard) {
+                        if let Some(other) = unmatched.next() {
+                            instantiated.push(format!("{}[.='{}']", path, literal(&other)));
+                            *entry = other;
+                        }
+                    }
+                }
+            }
+            SchemaNodeKind::Container => {
+                let children = data_children(node.children());
+                instantiate_template(value, other, &children, wildcard, &path, instantiated);
+            }
+            SchemaNodeKind::List => {
+                let (Some(entries), Some(others)) = (value.as_array_mut(), other.as_array()) else {
+                    continue;
+                };
+                let keys = node
+                    .list_keys()
+                    .map(|key| key.name().to_string())
+                    .collect::<Vec<_>>();
+                let children = data_children(node.children());
+                let matches = |entry: &Value, other: &Value| {
+                    keys.iter().all(|key| {
+                        entry.get(key).is_some_and(|value| {
+                            value.as_str() == Some(wildcard) || Some(value) == other.get(key)
+                        })
+                    })
+                };
+                let concrete = |entry: &Value| {
+                    keys.iter().all(|key| {
+                        entry
+                            .get(key)
+                            .is_some_and(|value| value.as_str() != Some(wildcard))
+                    })
+                };
+                // entries without wildcards are matched first, so that
+                // wildcard entries only take the entries left over
+                let mut taken = vec![false; others.len()];
+                let mut order = (0..entries.len()).collect::<Vec<_>>();
+                order.sort_by_key(|&i| !concrete(&entries[i]));
+                for i in order {
+                    let Some(j) =
+                        (0..others.len()).find(|&j| !taken[j] && matches(&entries[i], &others[j]))
+                    else {
+                        continue;
+                    };
+                    taken[j] = true;
+                    let wild = !concrete(&entries[i]);
+                    let entry = &mut entries[i];
+                    if wild {
+                        for key in &keys {
+                            entry[key] = others[j][key].clone();
+                        }
+                    }
+                    let predicates = keys
+                        .iter()
+                        .map(|key| format!("[{}='{}']", key, literal(&entry[key])))
+                        .collect::<String>();
+                    let path = format!("{}{}", path, predicates);
+                    if wild {
+                        instantiated.push(path.clone());
+                    }
+                    instantiate_template(
+                        &mut entries[i],
+                        &others[j],
+                        &children,
+                        wildcard,
+                        &path,
+                        instantiated,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A JSON scalar as it appears in a data path predicate.
+fn literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Apply the edits of an RFC 8072 YANG Patch document to a data tree, in
+/// order. Targets are absolute data paths, as written by
+/// `diff --format yang-patch`.
+fn yang_patch_apply(
+    ctx: &Arc<Context>,
+    dtree: &mut DataTree,
+    patch: &YangDoc,
+    errors: &mut Vec<ConvertError>,
+) {
+    use yang2::data::Data;
+
+    let Some(edits) = patch
+        .0
+        .pointer("/ietf-yang-patch:yang-patch/edit")
+        .and_then(|edits| edits.as_array())
+    else {
+        errors.push(ConvertError::new(
+            "",
+            "not a YANG Patch document, /ietf-yang-patch:yang-patch/edit is missing",
+        ));
+        return;
+    };
+
+    for (i, edit) in edits.iter().enumerate() {
+        let path = format!("/ietf-yang-patch:yang-patch/edit/{}", i);
+        let (Some(operation), Some(target)) = (
+            edit.get("operation").and_then(|op| op.as_str()),
+            edit.get("target").and_then(|target| target.as_str()),
+        ) else {
+            errors.push(ConvertError::new(
+                &path,
+                "edit needs an operation and a target",
+            ));
+            continue;
+        };
+        let exists = dtree.find_path(target).is_ok();
+        let value = edit.get("value");
+
+        let result = match operation {
+            "create" if exists => Err(format!("{}: already exists", target)),
+            "delete" if !exists => Err(format!("{}: does not exist", target)),
+            "delete" | "remove" if exists => dtree.remove(target).map_err(|err| err.to_string()),
+            "remove" => Ok(()),
+            "create" | "merge" => patch_value(ctx, dtree, target, value),
+            "replace" if exists => dtree
+                .remove(target)
+                .map_err(|err| err.to_string())
+                .and_then(|()| patch_value(ctx, dtree, target, value)),
+            "replace" => patch_value(ctx, dtree, target, value),
+            // the bindings can't position entries, which only matters for
+            // user ordered lists
+            "insert" | "move" => match ctx.find_path(&schema_path(target)) {
+                Ok(snode) if snode.is_user_ordered() => Err(format!(
+                    "{}: {} into ordered-by user lists is not supported",
+                    target, operation
+                )),
+                Ok(_) if operation == "move" && !exists => {
+                    Err(format!("{}: does not exist", target))
+                }
+                Ok(_) if operation == "move" => Ok(()),
+                Ok(_) if exists => Err(format!("{}: already exists", target)),
+                Ok(_) => patch_value(ctx, dtree, target, value),
+                Err(err) => Err(err.to_string()),
+            },
+            _ => Err(format!("unknown operation {}", operation)),
+        };
+        if let Err(err) = result {
+            let id = edit.get("edit-id").and_then(|id| id.as_str()).unwrap_or("");
+            errors.push(ConvertError::new(&path, format!("{}: {}", id, err)));
+        }
+    }
+}
+
+/// A data path with its predicates removed.
+fn schema_path(path: &str) -> String {
+    static PREDICATES: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r#"\[(?:[^\]'"]|'[^']*'|"[^"]*")*\]"#).unwrap());
+    PREDICATES.replace_all(path, "").into_owned()
+}
+
+/// Create the node at `target` from the value of a YANG Patch edit, which
+/// holds the target node itself, e.g. `{"module:list": [{...}]}` for a list
+/// entry.
+fn patch_value(
+    ctx: &Arc<Context>,
+    dtree: &mut DataTree,
+    target: &str,
+    value: Option<&serde_json::Value>,
+) -> Result<(), String> {
+    let value = match value.and_then(|value| value.as_object()) {
+        Some(value) if value.len() == 1 => value.values().next().unwrap(),
+        _ => return Err(format!("{}: value must hold the target node", target)),
+    };
+    // a list entry or leaf-list value is selected by the target's predicate
+    let value = match value.as_array() {
+        Some(entries) if target.ends_with(']') && entries.len() == 1 => &entries[0],
+        _ => value,
+    };
+    new_json_path(ctx, dtree, target, value)
+}
+
+/// Create the node at `path` with the JSON encoded contents `value`.
+fn new_json_path(
+    ctx: &Arc<Context>,
+    dtree: &mut DataTree,
+    path: &str,
+    value: &serde_json::Value,
+) -> Result<(), String> {
+    use serde_json::Value;
+
+    let predicate = |value: &str| match value.contains('\'') {
+        true => format!("\"{}\"", value),
+        false => format!("'{}'", value),
+    };
+
+    match value {
+        Value::Object(members) => {
+            dtree
+                .new_path(path, None, false)
+                .map_err(|err| format!("{}: {}", path, err))?;
+            for (name, member) in members {
+                new_json_path(ctx, dtree, &format!("{}/{}", path, name), member)?;
+            }
+        }
+        // type empty
+        Value::Array(a) if a.len() == 1 && a[0].is_null() => {
+            dtree
+                .new_path(path, None, false)
+                .map_err(|err| format!("{}: {}", path, err))?;
+        }
+        Value::Array(entries) => {
+            let snode = ctx
+                .find_path(&schema_path(path))
+                .map_err(|err| format!("{}: {}", path, err))?;
+            for entry in entries {
+                let keys = match snode.kind() {
+                    SchemaNodeKind::List => snode
+                        .list_keys()
+                        .map(|key| {
+                            let value = entry
+                                .get(key.name())
+                                .ok_or_else(|| format!("{}: missing key {}", path, key.name()))?;
+                            Ok(format!("[{}={}]", key.name(), predicate(&literal(value))))
+                        })
+                        .collect::<Result<String, String>>()?,
+                    _ => format!("[.={}]", predicate(&literal(entry))),
+                };
+                new_json_path(ctx, dtree, &format!("{}{}", path, keys), entry)?;
+            }
+        }
+        value => {
+            dtree
+                .new_path(path, Some(&literal(value)), false)
+                .map_err(|err| format!("{}: {}", path, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Trim the values of string leaves and leaf-lists in a JSON document and
+/// normalize their line endings to `\n`, so that values only differing in
+/// those compare equal.
+///
+/// This is done before the document is parsed, as changing the values of
+/// keys and leaf-list entries in a data tree would change their paths.
+/// Leaf-list entries, and entries of keyed lists, that only differed in
+/// whitespace are merged into the first of them, keeping the order of the
+/// others.
+fn normalize_whitespace(doc: &mut YangDoc, children: &[SchemaNode]) {
+    use serde_json::Value;
+
+    fn normalize(value: &mut Value) {
+        if let Value::String(s) = value {
+            let normalized = s.replace("\r\n", "\n").replace('\r', "\n");
+            *s = normalized.trim().to_string();
+        }
+    }
+
+    // the members of `from` that `into` lacks, recursively, and the array
+    // elements it doesn't have yet
+    fn merge(into: &mut Value, from: Value) {
+        match (into, from) {
+            (Value::Object(into), Value::Object(from)) => {
+                for (key, value) in from {
+                    match into.get_mut(&key) {
+                        Some(member) => merge(member, value),
+                        None => drop(into.insert(key, value)),
+                    }
+                }
+            }
+            (Value::Array(into), Value::Array(from)) => {
+                for el in from {
+                    if !into.contains(&el) {
+                        into.push(el);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn normalize_members(value: &mut Value, children: &[SchemaNode]) {
+        let Some(object) = value.as_object_mut() else {
+            return;
+        };
+        for (member, value) in object.iter_mut() {
+            let (module, name) = match member.split_once(':') {
+                Some((module, name)) => (Some(module), name),
+                None => (None, member.as_str()),
+            };
+            let Some(node) = children.iter().find(|child| {
+                child.name() == name && module.is_none_or(|module| child.module().name() == module)
+            }) else {
+                continue;
+            };
+            let string = node.base_type() == Some(DataValueType::String);
+            match node.kind() {
+                SchemaNodeKind::Leaf if string => normalize(value),
+                SchemaNodeKind::LeafList if string => {
+                    let Some(entries) = value.as_array_mut() else {
+                        continue;
+                    };
+                    let mut unique: Vec<Value> = vec![];
+                    for mut entry in std::mem::take(entries) {
+                        normalize(&mut entry);
+                        if !unique.contains(&entry) {
+                            unique.push(entry);
+                        }
+                    }
+                    *entries = unique;
+                }
+                SchemaNodeKind::Container => {
+                    normalize_members(value, &data_children(node.children()));
+                }
+                SchemaNodeKind::List => {
+                    let Some(entries) = value.as_array_mut() else {
+                        continue;
+                    };
+                    let entry_children = data_children(node.children());
+                    let keys = node
+                        .list_keys()
+                        .map(|key| key.name().to_string())
+                        .collect::<Vec<_>>();
+                    let mut merged: Vec<Value> = vec![];
+                    for mut entry in std::mem::take(entries) {
+                        normalize_members(&mut entry, &entry_children);
+                        let key_values = keys.iter().map(|key| entry.get(key)).collect::<Vec<_>>();
+                        let same = merged.iter_mut().find(|other| {
+                            !keys.is_empty()
+                                && keys
+                                    .iter()
+                                    .map(|key| other.get(key))
+                                    .eq(key_values.iter().copied())
+                        });
+                        match same {
+                            Some(other) => merge(other, entry),
+                            None => merged.push(entry),
+                        }
+                    }
+                    *entries = merged;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    normalize_members(&mut doc.0, children);
+}
+
+/// Remove the state data, i.e. the nodes of `config false` schema nodes,
+/// from a data tree.
+fn remove_state(dtree: &mut DataTree) -> Result<(), yang2::Error> {
+    use yang2::iter::NodeIterable;
+
+    let state = dtree
+        .traverse()
+        .filter(|dnode| !dnode.schema().is_config())
+        .filter(|dnode| {
+            dnode
+                .parent()
+                .is_none_or(|parent| parent.schema().is_config())
+        })
+        .map(|dnode| dnode.path())
+        .collect::<Vec<_>>();
+    for path in state {
+        dtree.remove(&path)?;
+    }
+    Ok(())
+}
+
+/// Summarize how the leaf values of several data trees differ: for every data
+/// path whose value isn't the same in all files, print each distinct value
+/// together with the files having it.
+fn diff_n(ctx: &Arc<Context>, files: &[PathBuf], empty_as_tree: bool, parse: &ParseOptions) {
+    let mut values: BTreeMap<String, BTreeMap<Option<String>, Vec<usize>>> = BTreeMap::new();
+    for (i, file) in files.iter().enumerate() {
+        let dtree = parse_data_file(ctx, file, empty_as_tree, &[], parse);
+
+        for dnode in dtree.traverse() {
+            if dnode.schema().is_list_key() {
+                continue;
+            }
+            if let Some(value) = dnode.value_canonical() {
+                values
+                    .entry(dnode.path())
+                    .or_default()
+                    .entry(Some(value))
+                    .or_default()
+                    .push(i);
+            }
+        }
+    }
+
+    for (path, mut by_value) in values {
+        let present = by_value.values().map(|files| files.len()).sum::<usize>();
+        if present < files.len() {
+            let missing = (0..files.len())
+                .filter(|i| !by_value.values().any(|files| files.contains(i)))
+                .collect();
+            by_value.insert(None, missing);
+        }
+        if by_value.len() < 2 {
+            continue;
+        }
+
+        println!("{}", path);
+        for (value, in_files) in by_value {
+            let in_files = in_files
+                .iter()
+                .map(|&i| files[i].display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            match value {
+                Some(value) => println!("  {:?}: {}", value, in_files),
+                None => println!("  (absent): {}", in_files),
+            }
+        }
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A JUnit XML report with one test case per converted file.
+fn junit_xml(results: &[(PathBuf, Vec<ConvertError>)]) -> String {
+    let failures = results
+        .iter()
+        .filter(|(_, errors)| !errors.is_empty())
+        .count();
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<testsuite name=\"nix-yang-tools\" tests=\"{}\" failures=\"{}\">",
+        results.len(),
+        failures
+    )
+    .unwrap();
+    for (input, errors) in results {
+        let name = xml_escape(&input.display().to_string());
+        if errors.is_empty() {
+            writeln!(out, "  <testcase name=\"{}\"/>", name).unwrap();
+            continue;
+        }
+        writeln!(out, "  <testcase name=\"{}\">", name).unwrap();
+        let messages = errors
+            .iter()
+            .map(|error| xml_escape(&error.to_string()))
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "    <failure message=\"{} errors\">{}</failure>",
+            errors.len(),
+            messages.join("\n")
+        )
+        .unwrap();
+        writeln!(out, "  </testcase>").unwrap();
+    }
+    out += "</testsuite>\n";
+    out
+}
+
+/// Breadcrumb of the `n` nearest container and list ancestors of a node,
+/// outermost first, with the key values of list entries.
+fn diff_context(dnode: &yang2::data::DataNodeRef, n: usize) -> String {
+    let mut crumbs = dnode
+        .ancestors()
+        .filter(|an| {
+            matches!(
+                an.schema().kind(),
+                SchemaNodeKind::Container | SchemaNodeKind::List
+            )
+        })
+        .take(n)
+        .map(|an| {
+            let keys = an
+                .list_keys()
+                .map(|key| {
+                    format!(
+                        "{}={}",
+                        key.schema().name(),
+                        key.value_canonical().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            if keys.is_empty() {
+                an.schema().name().to_string()
+            } else {
+                format!("{}[{}]", an.schema().name(), keys.join(","))
+            }
+        })
+        .collect::<Vec<_>>();
+    crumbs.reverse();
+    crumbs.join(" > ")
+}
+
+/// A data path without the module prefixes of its node names, for display.
+/// Predicate values are left alone.
+fn strip_namespace_prefixes(path: &str) -> String {
+    let mut out = String::new();
+    let mut quote = None;
+    // at the start of a node name, i.e. after `/` or `[`
+    let mut name_start = true;
+    let mut rest = path;
+    while let Some(c) = rest.chars().next() {
+        if name_start {
+            name_start = false;
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || "-_.".contains(c)))
+                .unwrap_or(rest.len());
+            if rest[end..].starts_with(':') {
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '/' | '[') => name_start = true,
+            _ => {}
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Remove the module prefixes of the member names of a YANG-style object,
+/// for display. Values such as identities keep theirs.
+fn strip_member_prefixes(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, mut member) in std::mem::take(object) {
+                strip_member_prefixes(&mut member);
+                let name = key.split_once(':').map_or(key.as_str(), |(_, name)| name);
+                object.insert(name.to_string(), member);
+            }
+        }
+        serde_json::Value::Array(entries) => entries.iter_mut().for_each(strip_member_prefixes),
+        _ => {}
+    }
+}
+
+/// Whether to color the diff output, from `--color always|never|auto`.
+///
+/// `auto` colors when stdout is a terminal and `NO_COLOR` isn't set.
+fn use_color(setting: &str) -> bool {
+    use std::io::IsTerminal;
+
+    match setting {
+        "always" => true,
+        "never" => false,
+        "auto" => {
+            std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty())
+        }
+        other => {
+            eprintln!("--color: expected always, never or auto, got {:?}", other);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn set_color(op: yang2::data::DataDiffOp, color: bool) {
+    let (code, marker) = match op {
+        yang2::data::DataDiffOp::Create => ("\x1b[92m", "+ "),
+        yang2::data::DataDiffOp::Delete => ("\x1b[91m", "- "),
+        yang2::data::DataDiffOp::Replace => ("\x1b[93m", "~ "),
+    };
+    if color {
+        print!("{}", code);
+    }
+    print!("{}", marker);
+}
+
+fn reset_color(color: bool) {
+    if color {
+        print!("\x1b[0m");
+    }
+}
+
+/// Dimmed text for annotations of the diff output.
+fn dim(text: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[2m{}\x1b[22m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The value following a flag in the command line.
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    Some(args.get(i + 1).expect(flag).clone())
+}
+
+/// Read the config file given with `--config`, or the one in the user's
+/// config directory if there is one.
+fn read_config(path: Option<String>) -> toml::Table {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let dir = match (
+                std::env::var_os("XDG_CONFIG_HOME"),
+                std::env::var_os("HOME"),
+            ) {
+                (Some(dir), _) => PathBuf::from(dir),
+                (None, Some(home)) => Path::new(&home).join(".config"),
+                (None, None) => return toml::Table::new(),
+            };
+            let path = dir.join("nix-yang-tools/config.toml");
+            if !path.exists() {
+                return toml::Table::new();
+            }
+            path
+        }
+    };
+    let config = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    config.parse::<toml::Table>().unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+/// The `[profiles.<name>]` tables of the config.
+fn profiles(config: &toml::Table) -> impl Iterator<Item = (&String, &toml::Table)> {
+    config
+        .get("profiles")
+        .and_then(|profiles| profiles.as_table())
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, profile)| Some((name, profile.as_table()?)))
+}
+
+/// The flags of a profile, after those of the profiles it extends.
+///
+/// Each key of a profile is the name of a flag without the dashes: `true`
+/// passes a switch, a string or number passes a value and an array passes
+/// the flag once per element, e.g. `search-dir = ["a", "b"]`.
+fn profile_args(config: &toml::Table, name: &str) -> Vec<String> {
+    let mut chain = vec![];
+    let mut next = Some(name.to_string());
+    while let Some(name) = next {
+        if chain.contains(&name) {
+            eprintln!("profile {} extends itself", name);
+            std::process::exit(1);
+        }
+        let Some((_, profile)) = profiles(config).find(|(profile, _)| **profile == name) else {
+            eprintln!("no profile {}", name);
+            std::process::exit(1);
+        };
+        next = match profile.get("extends") {
+            None => None,
+            Some(toml::Value::String(extends)) => Some(extends.clone()),
+            Some(extends) => {
+                eprintln!(
+                    "profile {}: extends: expected a profile name, got {}",
+                    name, extends
+                );
+                std::process::exit(1);
+            }
+        };
+        chain.push(name);
+    }
+
+    let mut args = vec![];
+    for name in chain.iter().rev() {
+        let profile = profiles(config)
+            .find(|(profile, _)| *profile == name)
+            .unwrap()
+            .1;
+        for (key, value) in profile {
+            if key == "extends" {
+                continue;
+            }
+            let flag = format!("--{}", key);
+            let values = match value {
+                toml::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    toml::Value::Boolean(true) => args.push(flag.clone()),
+                    toml::Value::Boolean(false) => {}
+                    toml::Value::String(value) => args.extend([flag.clone(), value.clone()]),
+                    toml::Value::Array(_) | toml::Value::Table(_) => {
+                        eprintln!(
+                            "profile {}: {}: expected a flag value, got {}",
+                            name, key, value
+                        );
+                        std::process::exit(1);
+                    }
+                    value => args.extend([flag.clone(), value.to_string()]),
+                }
+            }
+        }
+    }
+    args
+}
+
+fn main() -> Result<(), NixYangError> {
+    // split `--flag=value` into `--flag value`
+    let mut args = std::env::args()
+        .skip(1)
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                vec![flag.to_string(), value.to_string()]
+            }
+            _ => vec![arg],
+        })
+        .collect::<Vec<_>>();
+
+    let config = read_config(flag_value(&args, "--config"));
+    if let Some(name) = flag_value(&args, "--profile") {
+        // profile flags go first, so that flags given explicitly come later
+        let at = args.len().min(1);
+        args.splice(at..at, profile_args(&config, &name));
+    }
+    let mut args = args.into_iter();
+
+    let mode_name = args.next();
+
+    let mut convert_options = ConvertOptions::default();
+    let mut generate_options = GenerateOptions::default();
+    let mut direction = None;
+    let mut strict = false;
+    let mut timeout = None;
+    let mut search_dirs = vec![];
+    let mut recursive = false;
+    let mut schema_archives = vec![];
+    let mut imports = vec![];
+    let mut output_dir = None;
+    let mut emit_readme = false;
+    let mut grep_options = GrepOptions::default();
+    let mut emit_overlay = false;
+    let mut device_name = None;
+    let mut etc_file = None;
+    let mut home_manager_module = None;
+    let mut dump_ir = false;
+    let mut emit_mapping = None;
+    let mut mapping = None;
+    let mut formatter = None;
+    let mut cache_dir = None;
+    let mut junit_report = None;
+    let mut json_report = None;
+    let mut diff_format = DiffFormat::Text;
+    let mut emit = Emit::Json;
+    let mut patch_id = None;
+    let mut describe = false;
+    let mut strip_namespace_prefix = false;
+    let mut context = 0;
+    let mut color = "auto".to_string();
+    let mut empty_as_tree = false;
+    let mut whitespace_insensitive = false;
+    let mut config_only = false;
+    let mut count_only = false;
+    let mut include_schema_path = false;
+    let mut provenance = false;
+    let mut timestamp = true;
+    let mut meta_field_name = "_yang_schema_path".to_string();
+    let mut template_mode = false;
+    let mut wildcard = "__ANY__".to_string();
+    let mut describe_full = false;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rewrite-rule" => {
+                let rule = args.next().expect("--rewrite-rule <from=to>");
+                let Some((from, to)) = rule.split_once('=') else {
+                    eprintln!("--rewrite-rule: expected <from=to>, got {}", rule);
+                    std::process::exit(1);
+                };
+                convert_options
+                    .rewrite_rules
+                    .push((from.to_string(), to.to_string()));
+            }
+            "--auto-camelcase" => convert_options.auto_camelcase = true,
+            "--strip-prefixes" => convert_options.strip_prefixes = true,
+            "--keep-keys" => convert_options.keep_keys = true,
+            "--namespace-by-module" => convert_options.namespace_by_module = true,
+            "--key-prefix" => {
+                convert_options.key_prefix = Some(args.next().expect("--key-prefix <prefix>"))
+            }
+            "--coerce" => convert_options.coerce = true,
+            "--check" => convert_options.check = true,
+            // validate converted documents, and the data trees of other modes
+            "--validate" => {
+                convert_options.validate = true;
+                convert_options.parse.validate = Some(true);
+            }
+            "--no-validate" => {
+                convert_options.validate = false;
+                convert_options.parse.validate = Some(false);
+            }
+            "--parse-strict" => convert_options.parse.strict = Some(true),
+            "--parse-lenient" => convert_options.parse.strict = Some(false),
+            "--present-only" => convert_options.parse.present_only = true,
+            "--skip-unknown-modules" => convert_options.skip_unknown_modules = true,
+            "--reject-unknown" => convert_options.reject_unknown = true,
+            "--unwrap" => convert_options
+                .unwrap
+                .push(args.next().expect("--unwrap <member>")),
+            "--no-rewrap" => convert_options.no_rewrap = true,
+            "--deduplicate-lists" | "--deduplicate-last" => {
+                convert_options.deduplicate = Some(Deduplicate::Last)
+            }
+            "--deduplicate-error" | "--validate-keys" => {
+                convert_options.deduplicate = Some(Deduplicate::Error)
+            }
+            "--origin-filter" => match args.next() {
+                Some(origin)
+                    if ["intended", "system", "learned", "default"].contains(&origin.as_str()) =>
+                {
+                    convert_options.origins.push(origin)
+                }
+                origin => {
+                    eprintln!(
+                        "--origin-filter: expected intended, system, learned or default, got {}",
+                        origin.as_deref().unwrap_or("nothing")
+                    );
+                    std::process::exit(1);
+                }
+            },
+            "--json-pointer" => {
+                convert_options.json_pointer = Some(args.next().expect("--json-pointer <ptr>"))
+            }
+            "--schema-path" => {
+                convert_options.schema_path = Some(args.next().expect("--schema-path <yang-path>"))
+            }
+            "--ndjson" => convert_options.ndjson = true,
+            "--key-order" => {
+                let order = args.next().expect("--key-order <list-path>=<key,...>");
+                let Some((list, keys)) = order.split_once('=') else {
+                    eprintln!("--key-order: expected <list-path>=<key,...>, got {}", order);
+                    std::process::exit(1);
+                };
+                let keys = keys.split(',').map(str::to_string).collect();
+                convert_options.key_orders.insert(list.to_string(), keys);
+            }
+            "--emit-type-assertion" => convert_options.type_assertion = true,
+            "--prune-empty" => convert_options.prune_empty = true,
+            "--visible-paths" => {
+                let path = PathBuf::from(args.next().expect("--visible-paths <file>"));
+                convert_options.visible_paths = Some(VisiblePaths::read(&path));
+            }
+            "--max-entries-per-list" => {
+                let max = args.next().expect("--max-entries-per-list <n>");
+                let max = max.parse().unwrap_or_else(|err| {
+                    eprintln!("--max-entries-per-list: {}: {}", max, err);
+                    std::process::exit(1);
+                });
+                convert_options.max_entries_per_list = Some(max);
+            }
+            "--max-total-nodes" => {
+                let max = args.next().expect("--max-total-nodes <n>");
+                convert_options.max_total_nodes = Some(max.parse().unwrap_or_else(|err| {
+                    eprintln!("--max-total-nodes: {}: {}", max, err);
+                    std::process::exit(1);
+                }));
+            }
+            "--max-list-size" => {
+                let max = args.next().expect("--max-list-size <n>");
+                convert_options.max_list_size = Some(max.parse().unwrap_or_else(|err| {
+                    eprintln!("--max-list-size: {}: {}", max, err);
+                    std::process::exit(1);
+                }));
+            }
+            "--include-schema-path" => include_schema_path = true,
+            "--provenance" => provenance = true,
+            "--no-timestamp" => timestamp = false,
+            "--meta-field-name" => meta_field_name = args.next().expect("--meta-field-name <name>"),
+            "--ignore-type-errors" => convert_options.ignore_type_errors = true,
+            "--only" => convert_options
+                .only
+                .push(args.next().expect("--only <top-level-name>")),
+            "--ascii" => {
+                convert_options.ascii = true;
+                generate_options.ascii = true;
+            }
+            "--normalize-keys" => {
+                convert_options.normalize_keys = match args.next().as_deref() {
+                    Some("none") => NormalizeKeys::None,
+                    Some("trim") => NormalizeKeys::Trim,
+                    Some("lowercase") => NormalizeKeys::Lowercase,
+                    value => {
+                        eprintln!(
+                            "--normalize-keys: expected trim, lowercase or none, got {}",
+                            value.unwrap_or("nothing")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--invalid-chars" => {
+                convert_options.invalid_chars = match args.next().as_deref() {
+                    Some("error") => InvalidChars::Error,
+                    Some("strip") => InvalidChars::Strip,
+                    chars => {
+                        eprintln!(
+                            "--invalid-chars: expected error or strip, got {}",
+                            chars.unwrap_or("nothing")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--json-namespace" => {
+                convert_options.json_namespace = match args.next().as_deref() {
+                    Some("module-name") => JsonNamespace::ModuleName,
+                    Some("prefix") => JsonNamespace::Prefix,
+                    namespace => {
+                        eprintln!(
+                            "--json-namespace: expected module-name or prefix, got {}",
+                            namespace.unwrap_or("nothing")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--yang2nix" => direction = Some(ConvertMode::Yang2Nix),
+            "--nix2yang" => direction = Some(ConvertMode::Nix2Yang),
+            "--strict" => strict = true,
+            "--search-dir" => {
+                search_dirs.push(PathBuf::from(args.next().expect("--search-dir <dir>")))
+            }
+            "--recursive" => recursive = true,
+            "--cache-dir" => {
+                cache_dir = Some(PathBuf::from(args.next().expect("--cache-dir <dir>")))
+            }
+            "--schema-archive" | "--search-archive" => {
+                schema_archives.push(PathBuf::from(args.next().expect("--schema-archive <path>")))
+            }
+            "--import" => imports.push(args.next().expect("--import <module>")),
+            "--emit-readme" => emit_readme = true,
+            "--formatter" => formatter = Some(args.next().expect("--formatter <command>")),
+            "--names-only" => grep_options.names_only = true,
+            "--values-only" => grep_options.values_only = true,
+            "--limit" => {
+                let n = args.next().expect("--limit <n>");
+                grep_options.limit = Some(n.parse().unwrap_or_else(|err| {
+                    eprintln!("--limit: {}: {}", n, err);
+                    std::process::exit(1);
+                }));
+            }
+            "--emit-nixpkgs-overlay" => emit_overlay = true,
+            "--device-name" => device_name = Some(args.next().expect("--device-name <name>")),
+            // writes the yang2nix form, see `NixOptionsBuilder::home_manager`
+            "--emit-home-manager-module" => {
+                home_manager_module = Some(
+                    args.next()
+                        .expect("--emit-home-manager-module <module-name>"),
+                )
+            }
+            "--emit-etc-file" => etc_file = Some(args.next().expect("--emit-etc-file <path>")),
+            // already handled above
+            "--config" | "--profile" => drop(args.next()),
+            "--dump-ir" => dump_ir = true,
+            "--emit-mapping" => {
+                emit_mapping = Some(PathBuf::from(args.next().expect("--emit-mapping <file>")))
+            }
+            "--mapping" => mapping = Some(PathBuf::from(args.next().expect("--mapping <file>"))),
+            // only conditions with a description, see `print_when_conditions`
+            "--include-when-conditions" => generate_options.when_conditions = true,
+            "--emit-yang-path-comments" => generate_options.yang_path_comments = true,
+            "--type-overrides" => {
+                let path = PathBuf::from(args.next().expect("--type-overrides <file>"));
+                generate_options.type_overrides = read_type_overrides(&path);
+            }
+            "--warn-on-todo-types" => generate_options.warn_on_todo_types = true,
+            "--port-heuristic" => generate_options.port_heuristic = true,
+            // the default, failing on unmapped types; to override a profile
+            "--strict-types" => generate_options.warn_on_todo_types = false,
+            // keeping the lines of descriptions, for ASCII art and lists
+            "--max-description-length" => {
+                let n = args.next().expect("--max-description-length <n>");
+                generate_options.max_description_length = Some(n.parse().unwrap_or_else(|err| {
+                    eprintln!("--max-description-length: {}: {}", n, err);
+                    std::process::exit(1);
+                }));
+            }
+            // joining them into one line
+            "--description-max-length" => {
+                let n = args.next().expect("--description-max-length <n>");
+                generate_options.max_description_length = Some(n.parse().unwrap_or_else(|err| {
+                    eprintln!("--description-max-length: {}: {}", n, err);
+                    std::process::exit(1);
+                }));
+                generate_options.flatten_descriptions = true;
+            }
+            // `--report junit <path>` for CI, `--report <path>` for JSON
+            "--report" => match args.next().expect("--report [junit] <path>").as_str() {
+                "junit" => {
+                    junit_report = Some(PathBuf::from(args.next().expect("--report junit <path>")))
+                }
+                path => json_report = Some(PathBuf::from(path)),
+            },
+            "--output-dir" => {
+                output_dir = Some(PathBuf::from(args.next().expect("--output-dir <dir>")))
+            }
+            "--timeout" => {
+                let seconds = args.next().expect("--timeout <seconds>");
+                timeout = Some(seconds.parse::<u64>().unwrap_or_else(|err| {
+                    eprintln!("--timeout: {}: {}", seconds, err);
+                    std::process::exit(1);
+                }));
+            }
+            "--emit" => {
+                emit = match args.next().as_deref() {
+                    Some("json") => Emit::Json,
+                    Some("nix") => Emit::Nix,
+                    Some("nixos-test") => Emit::NixosTest,
+                    Some("terraform") => Emit::Terraform,
+                    emit => {
+                        eprintln!(
+                            "--emit: expected json, nix, nixos-test or terraform, got {}",
+                            emit.unwrap_or("nothing")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--format" => {
+                diff_format = match args.next().as_deref() {
+                    Some("text") => DiffFormat::Text,
+                    Some("yang-patch") => DiffFormat::YangPatch,
+                    Some("nix-overlay") => DiffFormat::NixOverlay,
+                    Some("json") => DiffFormat::Json,
+                    format => {
+                        eprintln!(
+                            "--format: expected text, yang-patch, nix-overlay or json, got {}",
+                            format.unwrap_or("nothing")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--patch-id" => patch_id = Some(args.next().expect("--patch-id <id>")),
+            "--empty-as-tree" => empty_as_tree = true,
+            "--compare-whitespace-insensitive" => whitespace_insensitive = true,
+            "--config-only" => config_only = true,
+            "--count-changes-only" | "--count-only" | "--quiet" => count_only = true,
+            "--template-mode" => template_mode = true,
+            "--wildcard" => wildcard = args.next().expect("--wildcard <value>"),
+            "--color" => color = args.next().expect("--color <always|never|auto>"),
+            "--context" => {
+                let n = args.next().expect("--context <n>");
+                context = n.parse::<usize>().unwrap_or_else(|err| {
+                    eprintln!("--context: {}: {}", n, err);
+                    std::process::exit(1);
+                });
+            }
+            "--describe" => describe = true,
+            "--strip-namespace-prefix" => strip_namespace_prefix = true,
+            "--describe-full" => {
+                describe = true;
+                describe_full = true;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut args = positional.into_iter();
+
+    if let Some(seconds) = timeout {
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_secs(seconds));
+            eprintln!("timed out after {} seconds", seconds);
+            // same exit code as timeout(1)
+            std::process::exit(124);
+        });
+    }
+
+    let mode = match mode_name.as_deref() {
+        Some("profiles") => {
+            match args.next().as_deref() {
+                Some("list") => {}
+                command => {
+                    eprintln!(
+                        "profiles: expected list, got {}",
+                        command.unwrap_or("nothing")
+                    );
+                    std::process::exit(1);
+                }
+            }
+            for (name, profile) in profiles(&config) {
+                match profile.get("extends").and_then(|extends| extends.as_str()) {
+                    Some(extends) => println!("{} (extends {})", name, extends),
+                    None => println!("{}", name),
+                }
+            }
+            std::process::exit(0);
+        }
+        Some("--version" | "version") => Mode::Version,
+        Some("convert") => Mode::Convert(
+            direction,
+            std::fs::canonicalize(args.next().expect("filename")).expect("realpath"),
+        ),
+        // deprecated shorthands for `convert --yang2nix` and `convert --nix2yang`
+        Some(name @ ("yang2nix" | "nix2yang")) => {
+            eprintln!(
+                "warning: {} is deprecated, use convert --{} instead",
+                name, name
+            );
+            let direction = match name {
+                "yang2nix" => ConvertMode::Yang2Nix,
+                _ => ConvertMode::Nix2Yang,
+            };
+            Mode::Convert(
+                Some(direction),
+                std::fs::canonicalize(args.next().expect("filename")).expect("realpath"),
+            )
+        }
+        Some("nix_options") => Mode::NixOptions,
+        Some("probe") => Mode::Probe,
+        Some("grep") => Mode::Grep(
+            args.next().expect("grep <pattern> [datafile]"),
+            args.next().map(PathBuf::from),
+        ),
+        Some("diffn") => Mode::DiffN(
+            args.by_ref()
+                .map(|file| {
+                    std::fs::canonicalize(&file).unwrap_or_else(|err| {
+                        eprintln!("{}: {}", file, err);
+                        std::process::exit(1);
+                    })
+                })
+                .collect(),
+        ),
+        Some("diff") => Mode::Diff(
+            PathBuf::from(args.next().expect("filename")),
+            PathBuf::from(args.next().expect("filename")),
+        ),
+        Some("--self-test" | "self-test") => Mode::SelfTest,
+        Some("--verify" | "verify") => Mode::Verify {
+            reference: PathBuf::from(args.next().expect("verify <options.nix>")),
+        },
+        Some("--yang-patch-apply" | "yang-patch-apply") => Mode::PatchApply {
+            base: PathBuf::from(args.next().expect("yang-patch-apply <base> <patch>")),
+            patch: PathBuf::from(args.next().expect("yang-patch-apply <base> <patch>")),
+        },
+        _ => panic!(
+            "mode: convert yang2nix nix2yang nix_options probe diff diffn grep yang-patch-apply self-test verify version"
+        ),
+    };
+
+    if let Ok(dirs) = std::env::var("YANG_SCHEMAS_PATH") {
+        search_dirs.extend(
+            dirs.split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        );
+    }
+    if let Ok(dir) = std::env::var("YANG_SCHEMAS_DIR") {
+        search_dirs.push(PathBuf::from(dir));
+    }
+    // With a cache directory, the directories the modules were found in on
+    // an earlier run replace the search directories, saving the walk of
+    // recursive search directories and the extraction of archives, which
+    // are extracted into the cache directory to stay there. libyang has no
+    // way to store the compiled context itself.
+    let cache_key = context_cache_key(&search_dirs, &schema_archives, &imports, recursive);
+    let cached_dirs = cache_dir
+        .as_deref()
+        .and_then(|dir| cached_module_dirs(dir, &cache_key));
+    // extracted into temporary directories without a cache directory,
+    // searched recursively
+    let mut archives = vec![];
+    if let Some(dirs) = &cached_dirs {
+        search_dirs = dirs.clone();
+    } else {
+        if recursive {
+            let mut subdirs = vec![];
+            for dir in &search_dirs {
+                subdirectories(dir, &mut subdirs).map_err(|err| {
+                    std::io::Error::new(err.kind(), format!("{}: {}", dir.display(), err))
+                })?;
+            }
+            search_dirs.extend(subdirs);
+        }
+        for (i, archive) in schema_archives.iter().enumerate() {
+            let dir = match &cache_dir {
+                Some(cache_dir) => {
+                    let dir = cache_dir.join("archives").join(i.to_string());
+                    let _ = std::fs::remove_dir_all(&dir);
+                    std::fs::create_dir_all(&dir)
+                        .and_then(|()| unpack_schema_archive(archive, &dir))
+                        .map(|()| dir)
+                }
+                None => extract_schema_archive(archive).map(|dir| {
+                    let path = dir.path().to_path_buf();
+                    archives.push(dir);
+                    path
+                }),
+            };
+            let dir = dir.unwrap_or_else(|err| {
+                eprintln!("{}: {}", archive.display(), err);
+                std::process::exit(1);
+            });
+            search_dirs.push(dir.clone());
+            subdirectories(&dir, &mut search_dirs)
+                .map_err(|err| NixYangError::Schema(format!("{}: {}", archive.display(), err)))?;
+        }
+    }
+
+    // Initialize context.
+    let mut ctx = Context::new(ContextFlags::NO_YANGLIBRARY)
+        .map_err(|err| NixYangError::Schema(format!("Failed to create context: {}", err)))?;
+
+    for dir in &search_dirs {
+        ctx.set_searchdir(dir)
+            .map_err(|err| NixYangError::Schema(format!("{}: {}", dir.display(), err)))?;
+    }
+
+    // Additional modules, e.g. deviations, which libyang applies to the
+    // modules they target when compiling the context.
+    let mut load_failed = None;
+    for name in std::iter::once("rtbrick-config").chain(imports.iter().map(String::as_str)) {
+        if let Err(err) = ctx.load_module(name, None, &[]) {
+            let searched = search_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>();
+            load_failed = Some(format!(
+                "Failed to load module {}: {}\nSearched: {}",
+                name,
+                err,
+                searched.join(", ")
+            ));
+            break;
+        }
+    }
+    // the modules are compiled into the context, so the extracted archives
+    // can go before any of the modes exit the process
+    drop(archives);
+    if let Some(message) = load_failed {
+        // e.g. a submodule in a directory without modules, which the next
+        // run finds again
+        if let (Some(dir), Some(_)) = (&cache_dir, &cached_dirs) {
+            eprintln!("Removing the cached module directories");
+            let _ = std::fs::remove_file(dir.join("context.json"));
+        }
+        return Err(NixYangError::Schema(message));
+    }
+    if let (Some(dir), None) = (&cache_dir, &cached_dirs) {
+        if let Err(err) = write_context_cache(dir, &cache_key, &ctx) {
+            eprintln!("warning: {}: {}", dir.display(), err);
+        }
+    }
+
+    //for module in ctx.modules(false) {
+    //    eprintln!("loaded module {}@{:?}", module.name(), module.revision());
+    //}
+
+    let ctx = Arc::new(ctx);
+
+    if include_schema_path {
+        // the field would be taken for, or turn into, a schema node
+        let conflict = ctx.traverse().find(|node| {
+            node.name() == meta_field_name
+                || convert_options.nix_name(node.name()) == meta_field_name
+        });
+        if let Some(node) = conflict {
+            eprintln!(
+                "--meta-field-name {}: conflicts with {}",
+                meta_field_name,
+                node.path(SchemaPathFormat::DATA)
+            );
+            std::process::exit(1);
+        }
+        convert_options.schema_path_field = Some(meta_field_name);
+    }
+
+    // the key orders of --key-order take precedence over the mapping's
+    if let Some(path) = &mapping {
+        for (list, keys) in read_mapping(&ctx, path) {
+            convert_options.key_orders.entry(list).or_insert(keys);
+        }
+    }
+
+    let module = ctx.get_module_latest("rtbrick-config").unwrap();
+
+    let roots = module.data();
+
+    // with --namespace-by-module, options for all the loaded modules with
+    // data nodes, not only rtbrick-config
+    let generated_modules = if convert_options.namespace_by_module {
+        std::iter::once("rtbrick-config")
+            .chain(imports.iter().map(String::as_str))
+            .filter_map(|name| ctx.get_module_latest(name))
+            .filter(|module| module.data().next().is_some())
+            .collect::<Vec<_>>()
+    } else {
+        vec![ctx.get_module_latest("rtbrick-config").unwrap()]
+    };
+    let namespaced = convert_options.namespace_by_module;
+
+    let (mode, path) = match mode {
+        Mode::Convert(mode, path) => (mode, path),
+        Mode::Version => {
+            println!(
+                "{} {} yang2={} module={}@{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                YANG2_VERSION,
+                module.name(),
+                module.revision().unwrap_or("none")
+            );
+            std::process::exit(0);
+        }
+        Mode::Probe => {
+            let found = probe(roots, &generate_options);
+            std::process::exit(if strict && found > 0 { 1 } else { 0 });
+        }
+        Mode::NixOptions if dump_ir => {
+            let mut dumper = IrDumper {
+                options: &generate_options,
+                path: vec![],
+                nodes: vec![],
+            };
+            visit_schema(roots, &mut dumper);
+            println!("{}", serde_json::to_string_pretty(&dumper.nodes).unwrap());
+            std::process::exit(0);
+        }
+        Mode::NixOptions if emit == Emit::Terraform => {
+            print!("{}", terraform_variables(roots, &generate_options));
+            std::process::exit(0);
+        }
+        Mode::NixOptions if emit == Emit::NixosTest => {
+            print!("{}", nixos_test(roots, &generate_options));
+            std::process::exit(0);
+        }
+        Mode::NixOptions => {
+            if let Some(path) = &emit_mapping {
+                let records = option_mapping(module.data(), &generate_options);
+                write_atomic(path, serde_json::to_string_pretty(&records).unwrap())?;
+            }
+            let mut readme = emit_readme.then(Readme::default);
+            match &output_dir {
+                _ if home_manager_module.is_some() => {
+                    let name = home_manager_module.as_deref().unwrap();
+                    let mut builder =
+                        NixOptionsBuilder::home_manager(&generate_options, &mut readme, name);
+                    builder.add_modules(&generated_modules, namespaced);
+                    print!("{}", builder.finish());
+                }
+                Some(_) if namespaced => {
+                    eprintln!("--namespace-by-module: generates a single file, not --output-dir");
+                    std::process::exit(1);
+                }
+                Some(dir) => write_nix_options_dir(dir, roots, &generate_options, &mut readme)?,
+                None => {
+                    let mut builder = NixOptionsBuilder::new(&generate_options, &mut readme);
+                    builder.add_modules(&generated_modules, namespaced);
+                    print!("{}", builder.finish());
+                }
+            }
+            if let Some(readme) = readme {
+                let path = output_dir.unwrap_or_default().join("README.md");
+                write_atomic(&path, readme.render())?;
+            }
+            std::process::exit(0);
+        }
+        Mode::Verify { reference } => {
+            let mut readme = None;
+            let generated = match &home_manager_module {
+                Some(name) => {
+                    let mut builder =
+                        NixOptionsBuilder::home_manager(&generate_options, &mut readme, name);
+                    builder.add_modules(&generated_modules, namespaced);
+                    builder.finish()
+                }
+                None => NixOptionsBuilder::new(&generate_options, &mut readme)
+                    .add_modules(&generated_modules, namespaced)
+                    .finish(),
+            };
+            let generated = match &formatter {
+                Some(command) => format_nix(command, &generated)?,
+                None => generated,
+            };
+            let expected = std::fs::read_to_string(&reference)?;
+            if generated == expected {
+                std::process::exit(0);
+            }
+            eprintln!("{} is not up to date", reference.display());
+            print!("{}", line_diff(&expected, &generated));
+            std::process::exit(1);
+        }
+        Mode::Grep(pattern, data) => {
+            let pattern = regex::RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .unwrap_or_else(|err| {
+                    eprintln!("grep: {}", err);
+                    std::process::exit(1);
+                });
+            let parse = &convert_options.parse;
+            let data = data.map(|data| parse_data_file(&ctx, &data, false, &[], parse));
+            for line in grep(&ctx, &pattern, data.as_ref(), &grep_options) {
+                println!("{}", line);
+            }
+            std::process::exit(0);
+        }
+        Mode::DiffN(files) => {
+            diff_n(&ctx, &files, empty_as_tree, &convert_options.parse);
+            std::process::exit(0);
+        }
+        Mode::SelfTest => match self_test(&ctx, &convert_options) {
+            Ok(nodes) => {
+                println!("self-test passed, round-tripped {} data nodes", nodes);
+                std::process::exit(0);
+            }
+            Err(error) => {
+                eprintln!("self-test failed: {}", error);
+                std::process::exit(1);
+            }
+        },
+        Mode::PatchApply { base, patch } => {
+            use yang2::data::{Data, DataPrinterFlags};
+
+            let parse = &convert_options.parse;
+            let mut dtree = parse_data_file(&ctx, &base, empty_as_tree, &[], parse);
+            let patch = read_data_json(&patch, false, &[]);
+            let mut errors = vec![];
+            yang_patch_apply(&ctx, &mut dtree, &patch, &mut errors);
+            if !errors.is_empty() {
+                for error in &errors {
+                    eprintln!("{}", error);
+                }
+                std::process::exit(1);
+            }
+            let data = dtree
+                .print_string(DataFormat::JSON, DataPrinterFlags::WITH_SIBLINGS)
+                .expect("Failed to print data tree")
+                .unwrap_or_default();
+            print!("{}", data);
+            std::process::exit(0);
+        }
+        Mode::Diff(file1, file2) => {
+            if let DiffFormat::NixOverlay = diff_format {
+                if config_only {
+                    eprintln!("--config-only: can't be combined with --format nix-overlay");
+                    std::process::exit(1);
+                }
+                if count_only {
+                    eprintln!("--count-changes-only: can't be combined with --format nix-overlay");
+                    std::process::exit(1);
+                }
+                if convert_options.visible_paths.is_some() {
+                    eprintln!("--visible-paths: can't be combined with --format nix-overlay");
+                    std::process::exit(1);
+                }
+                let [left, right] = [&file1, &file2].map(|file| {
+                    let empty = std::fs::read_to_string(file).is_ok_and(|d| d.trim().is_empty());
+                    if empty_as_tree && empty {
+                        return NixDoc(serde_json::json!({}));
+                    }
+                    read_documents(file, &convert_options)
+                        .and_then(|mut documents| {
+                            let doc = YangDoc(documents.pop().unwrap_or_default());
+                            let report = &mut Report::default();
+                            yang_to_nix(&ctx, file, doc, &convert_options, report)
+                        })
+                        .unwrap_or_else(|errors| {
+                            for error in &errors {
+                                eprintln!("{}: {}", file.display(), error);
+                            }
+                            std::process::exit(1);
+                        })
+                });
+                let mut removals = vec![];
+                let overlay = nix_overlay(&left, &right, &mut removals);
+                let overlay = serde_json::json!({
+                    "overlay": overlay.unwrap_or_else(|| serde_json::json!({})),
+                    "removals": removals,
+                });
+                match emit {
+                    Emit::Nix => println!("{}", nix_value(&overlay, "", convert_options.ascii)),
+                    _ => println!("{}", serde_json::to_string_pretty(&overlay).unwrap()),
+                }
+                std::process::exit(0);
+            }
+
+            let color = use_color(&color);
+            use yang2::data::{Data, DataDiffFlags, DataPrinterFlags};
+
+            // Parse data trees from JSON strings.
+            let origins = &convert_options.origins;
+            let parse = &convert_options.parse;
+            let mut instantiated = vec![];
+            let (mut dtree1, mut dtree2) = if template_mode || whitespace_insensitive {
+                // the first file is the template in template mode
+                let mut template = read_data_json(&file1, empty_as_tree, origins);
+                let mut other = read_data_json(&file2, empty_as_tree, origins);
+                let top_level = data_children(
+                    ctx.modules(true)
+                        .filter(|module| module.is_implemented())
+                        .flat_map(|module| module.data()),
+                );
+                if whitespace_insensitive {
+                    normalize_whitespace(&mut template, &top_level);
+                    normalize_whitespace(&mut other, &top_level);
+                }
+                if template_mode {
+                    instantiate_template(
+                        &mut template.0,
+                        &other.0,
+                        &top_level,
+                        &wildcard,
+                        "",
+                        &mut instantiated,
+                    );
+                }
+                (
+                    parse_data_string(&ctx, &file1, &template.0.to_string(), parse),
+                    parse_data_string(&ctx, &file2, &other.0.to_string(), parse),
+                )
+            } else {
+                (
+                    parse_data_file(&ctx, &file1, empty_as_tree, origins, parse),
+                    parse_data_file(&ctx, &file2, empty_as_tree, origins, parse),
+                )
+            };
+            // state differences are expected when comparing intended config
+            if config_only {
+                for (dtree, file) in [(&mut dtree1, &file1), (&mut dtree2, &file2)] {
+                    remove_state(dtree).unwrap_or_else(|err| {
+                        eprintln!("{}: {}", file.display(), libyang_error(&err));
+                        std::process::exit(1);
+                    });
+                }
+            }
+            // only compare what both sides can see, rather than reporting
+            // what one of them is filtered by NACM as deleted
+            if let Some(visible) = &convert_options.visible_paths {
+                for (dtree, file) in [(&mut dtree1, &file1), (&mut dtree2, &file2)] {
+                    restrict_to_visible(dtree, visible).unwrap_or_else(|err| {
+                        eprintln!("{}: {}", file.display(), libyang_error(&err));
+                        std::process::exit(1);
+                    });
+                }
+            }
+
+            // Compare data trees.
+            let diff = dtree1
+                .diff(&dtree2, DataDiffFlags::empty())
+                .expect("Failed to compare data trees");
+
+            let dtree1_root = dtree1.reference();
+            let dtree2_root = dtree2.reference();
+
+            // the exit status tells whether there were changes
+            if count_only {
+                let (mut created, mut deleted, mut replaced) = (0, 0, 0);
+                // a leaf-list is one update, as in the other formats
+                let mut leaf_lists = std::collections::HashSet::new();
+                for (op, dnode) in diff.iter() {
+                    if dnode.schema().kind() == SchemaNodeKind::LeafList {
+                        let path = leaf_list_path(&dnode.path()).to_string();
+                        if leaf_lists.insert(path) {
+                            replaced += 1;
+                        }
+                        continue;
+                    }
+                    match op {
+                        yang2::data::DataDiffOp::Create => created += 1,
+                        yang2::data::DataDiffOp::Delete => deleted += 1,
+                        yang2::data::DataDiffOp::Replace => replaced += 1,
+                    }
+                }
+                println!(
+                    "{} created, {} deleted, {} replaced",
+                    created, deleted, replaced
+                );
+                let changed = created + deleted + replaced > 0;
+                std::process::exit(changed as i32);
+            }
+
+            if let DiffFormat::YangPatch = diff_format {
+                let edits = diff
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (op, dnode))| {
+                        let target = dnode.path();
+                        let mut edit = serde_json::json!({
+                            "edit-id": format!("edit-{}", i + 1),
+                            "operation": match op {
+                                yang2::data::DataDiffOp::Create => "create",
+                                yang2::data::DataDiffOp::Delete => "delete",
+                                yang2::data::DataDiffOp::Replace => "replace",
+                            },
+                            "target": target,
+                        });
+                        if op != yang2::data::DataDiffOp::Delete {
+                            let value = dtree2_root
+                                .as_ref()
+                                .unwrap()
+                                .find_path(&target)
+                                .unwrap()
+                                .print_string(DataFormat::JSON, DataPrinterFlags::empty())
+                                .expect("Failed to print data diff")
+                                .unwrap();
+                            edit["value"] = serde_json::from_str(&value).unwrap();
+                        }
+                        edit
+                    })
+                    .collect::<Vec<_>>();
+
+                let patch = serde_json::json!({
+                    "ietf-yang-patch:yang-patch": {
+                        "patch-id": patch_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                        "edit": edits,
+                    }
+                });
+                println!("{}", serde_json::to_string_pretty(&patch).unwrap());
+                std::process::exit(0);
+            }
+
+            // the entries of a leaf-list are compared by value, under a
+            // single operation for the whole leaf-list
+            let mut leaf_lists = std::collections::HashSet::new();
+
+            // only for display, lookups need the qualified paths
+            let display_path = |path: &str| {
+                if strip_namespace_prefix {
+                    strip_namespace_prefixes(path)
+                } else {
+                    path.to_string()
+                }
+            };
+
+            // large diffs touch the same leaves over and over
+            let mut descriptions = std::collections::HashMap::new();
+            let mut describe_node = |schema: &SchemaNode| -> Option<String> {
+                descriptions
+                    .entry(schema.path(SchemaPathFormat::DATA))
+                    .or_insert_with(|| diff_description(schema, describe_full))
+                    .clone()
+            };
+
+            if let DiffFormat::Json = diff_format {
+                let mut ops = vec![];
+                for (op, dnode) in diff.iter() {
+                    let schema = dnode.schema();
+                    let path = dnode.path();
+                    let description = describe.then(|| describe_node(&schema)).flatten();
+                    if schema.kind() == SchemaNodeKind::LeafList {
+                        let path = leaf_list_path(&path);
+                        if !leaf_lists.insert(path.to_string()) {
+                            continue;
+                        }
+                        let ordered = schema.is_user_ordered();
+                        let changes = leaf_list_changes(&dtree1, &dtree2, path, ordered);
+                        let values = |marker| {
+                            let values = changes.iter().filter(move |(op, _)| *op == marker);
+                            values.map(|(_, value)| value.clone()).collect::<Vec<_>>()
+                        };
+                        let mut entry = serde_json::json!({
+                            "op": "leaf-list-update",
+                            "path": display_path(path),
+                            "added": values('+'),
+                            "removed": values('-'),
+                        });
+                        if let Some(description) = description {
+                            entry["description"] = description.into();
+                        }
+                        ops.push(entry);
+                        continue;
+                    }
+                    let mut entry = serde_json::json!({
+                        "op": match op {
+                            yang2::data::DataDiffOp::Create => "create",
+                            yang2::data::DataDiffOp::Delete => "delete",
+                            yang2::data::DataDiffOp::Replace => "replace",
+                        },
+                        "path": display_path(&path),
+                    });
+                    let trees = match op {
+                        yang2::data::DataDiffOp::Create => [None, dtree2_root.as_ref()],
+                        yang2::data::DataDiffOp::Delete => [dtree1_root.as_ref(), None],
+                        yang2::data::DataDiffOp::Replace => {
+                            [dtree1_root.as_ref(), dtree2_root.as_ref()]
+                        }
+                    };
+                    for (name, tree) in ["before", "after"].into_iter().zip(trees) {
+                        let Some(tree) = tree else { continue };
+                        let value = tree
+                            .find_path(&path)
+                            .unwrap()
+                            .print_string(DataFormat::JSON, DataPrinterFlags::empty())
+                            .expect("Failed to print data diff")
+                            .unwrap();
+                        let mut value = serde_json::from_str(&value).unwrap();
+                        if strip_namespace_prefix {
+                            strip_member_prefixes(&mut value);
+                        }
+                        entry[name] = value;
+                    }
+                    if let Some(description) = description {
+                        entry["description"] = description.into();
+                    }
+                    ops.push(entry);
+                }
+                println!("{}", serde_json::to_string_pretty(&ops).unwrap());
+                std::process::exit(0);
+            }
+
+            // libyang prints each member on a line of its own
+            let member_prefix = strip_namespace_prefix
+                .then(|| regex::Regex::new(r#"^(\s*")[^"/:]+:([^"]*":)"#).unwrap());
+
+            for path in &instantiated {
+                println!("i @{}", display_path(path));
+            }
+            for (op, dnode) in diff.iter() {
+                let leaf_list = dnode.schema().kind() == SchemaNodeKind::LeafList;
+                if leaf_list {
+                    let path = leaf_list_path(&dnode.path()).to_string();
+                    if !leaf_lists.insert(path.clone()) {
+                        continue;
+                    }
+                    set_color(yang2::data::DataDiffOp::Replace, color);
+                    println!("LeafListUpdate @{}", display_path(&path));
+                } else {
+                    set_color(op, color);
+                    println!("{:?} @{}", op, display_path(&dnode.path()));
+                }
+                if context > 0 {
+                    let crumbs = diff_context(&dnode, context);
+                    if !crumbs.is_empty() {
+                        set_color(op, color);
+                        println!("{}", dim(&format!("in {}", crumbs), color));
+                    }
+                }
+                if describe {
+                    let description = describe_node(&dnode.schema());
+                    for line in description.iter().flat_map(|d| d.lines()) {
+                        set_color(op, color);
+                        println!("{}", dim(&format!("# {}", line.trim()), color));
+                    }
+                }
+                if leaf_list {
+                    let path = leaf_list_path(&dnode.path()).to_string();
+                    let ordered = dnode.schema().is_user_ordered();
+                    for (change, value) in leaf_list_changes(&dtree1, &dtree2, &path, ordered) {
+                        match change {
+                            '-' => set_color(yang2::data::DataDiffOp::Delete, color),
+                            _ => set_color(yang2::data::DataDiffOp::Create, color),
+                        }
+                        println!("{}", value);
+                    }
+                    println!();
+                    continue;
+                }
+                let diffs_to_print = match op {
+                    yang2::data::DataDiffOp::Replace => vec![
+                        (
+                            yang2::data::DataDiffOp::Delete,
+                            dtree1_root
+                                .as_ref()
+                                .unwrap()
+                                .find_path(&dnode.path())
+                                .unwrap(),
+                        ),
+                        (
+                            yang2::data::DataDiffOp::Create,
+                            dtree2_root
+                                .as_ref()
+                                .unwrap()
+                                .find_path(&dnode.path())
+                                .unwrap(),
+                        ),
+                    ],
+                    yang2::data::DataDiffOp::Delete => vec![(
+                        op,
+                        dtree1_root
+                            .as_ref()
+                            .unwrap()
+                            .find_path(&dnode.path())
+                            .unwrap(),
+                    )],
+                    yang2::data::DataDiffOp::Create => vec![(
+                        op,
+                        dtree2_root
+                            .as_ref()
+                            .unwrap()
+                            .find_path(&dnode.path())
+                            .unwrap(),
+                    )],
+                };
+
+                for (op, dnode) in diffs_to_print {
+                    let diff_str = dnode
+                        .print_string(DataFormat::JSON, DataPrinterFlags::empty())
+                        .expect("Failed to print data diff")
+                        .unwrap();
+                    for line in diff_str.lines() {
+                        set_color(op, color);
+                        match &member_prefix {
+                            Some(prefix) => println!("{}", prefix.replace(line, "$1$2")),
+                            None => println!("{}", line),
+                        }
+                    }
+                }
+                println!();
+            }
+            reset_color(color);
+            std::process::exit(0);
+        }
+    };
+
+    // the documents of a single input, read to detect the direction
+    let mut first = None;
+    let mode = match mode {
+        Some(mode) => mode,
+        None if path.is_dir() => {
+            eprintln!(
+                "{}: can't tell whether the input is YANG-style or Nix-style, use --yang2nix or --nix2yang",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+        None => match read_input(&ctx, &path, None, &convert_options) {
+            Ok(docs) => {
+                let mode = docs.first().map_or(ConvertMode::Yang2Nix, Doc::mode);
+                first = Some(docs);
+                mode
+            }
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("{}: {}", path.display(), error);
+                }
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let overlay_device = match mode {
+        ConvertMode::Yang2Nix if emit_overlay => Some(device_name.unwrap_or_else(|| {
+            eprintln!("--emit-nixpkgs-overlay: needs --device-name");
+            std::process::exit(1);
+        })),
+        _ if emit_overlay => {
+            eprintln!("--emit-nixpkgs-overlay: only for yang2nix");
+            std::process::exit(1);
+        }
+        _ => None,
+    };
+    let etc_file = match mode {
+        ConvertMode::Yang2Nix if emit_overlay && etc_file.is_some() => {
+            eprintln!("--emit-etc-file: can't be combined with --emit-nixpkgs-overlay");
+            std::process::exit(1);
+        }
+        ConvertMode::Yang2Nix => etc_file,
+        _ if etc_file.is_some() => {
+            eprintln!("--emit-etc-file: only for yang2nix");
+            std::process::exit(1);
+        }
+        _ => None,
+    };
+    let emit_nix = match mode {
+        ConvertMode::Yang2Nix => emit == Emit::Nix,
+        _ if emit == Emit::Nix => {
+            eprintln!("--emit nix: only for yang2nix");
+            std::process::exit(1);
+        }
+        _ => false,
+    };
+    let nix_output = emit_nix || overlay_device.is_some() || etc_file.is_some();
+    if convert_options.ndjson && nix_output {
+        eprintln!("--ndjson: only for JSON output");
+        std::process::exit(1);
+    }
+    if provenance && !nix_output {
+        eprintln!("--provenance: only for Nix output");
+        std::process::exit(1);
+    }
+
+    let inputs = if path.is_dir() {
+        if !convert_options.check {
+            eprintln!(
+                "{}: directories can only be converted with --check",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+        let mut inputs = vec![];
+        json_files(&path, &mut inputs)?;
+        inputs
+    } else {
+        vec![path]
+    };
+
+    let mut results = vec![];
+    let mut reports = vec![];
+    for input in inputs {
+        let mut errors = vec![];
+        let mut report = Report::new(json_report.is_some());
+        let docs = match first.take() {
+            Some(docs) => Ok(docs),
+            None => read_input(&ctx, &input, Some(&mode), &convert_options),
+        };
+        let mut docs = match docs {
+            Ok(docs) => convert_docs(
+                &ctx,
+                &input,
+                docs,
+                &convert_options,
+                &mut errors,
+                &mut report,
+            ),
+            Err(read_errors) => {
+                errors = read_errors;
+                vec![]
+            }
+        };
+
+        if !errors.is_empty() {
+            for error in &errors {
+                eprintln!("{}: {}", input.display(), error);
+            }
+        } else if convert_options.ndjson && !convert_options.check {
+            // printed as they are, in whichever representation
+            for doc in docs {
+                println!("{}", json_string(&doc.into_inner(), convert_options.ascii));
+            }
+        } else if !convert_options.check {
+            let data = docs.pop().map(Doc::into_inner).unwrap_or_default();
+            if provenance {
+                print!("{}", provenance_comment(&ctx, &input, &data, timestamp));
+            }
+            match &overlay_device {
+                Some(device_name) => println!(
+                    "final: prev: {{\n  networkConfigs.{} = {};\n}}",
+                    nix_attr_name(device_name, convert_options.ascii),
+                    nix_value(&data, "  ", convert_options.ascii)
+                ),
+                None => match &etc_file {
+                    Some(etc_path) => println!(
+                        "{{\n  environment.etc.{}.text = builtins.toJSON {};\n}}",
+                        nix_value(
+                            &serde_json::Value::from(etc_path.as_str()),
+                            "",
+                            convert_options.ascii
+                        ),
+                        nix_value(&data, "  ", convert_options.ascii)
+                    ),
+                    None if emit_nix => println!("{}", nix_value(&data, "", convert_options.ascii)),
+                    None => println!("{}", json_string(&data, convert_options.ascii)),
+                },
+            }
+        }
+        results.push((input, errors));
+        reports.push(report);
+    }
+
+    let failed = results
+        .iter()
+        .filter(|(_, errors)| !errors.is_empty())
+        .count();
+    if convert_options.check {
+        eprintln!("checked {} files, {} failed", results.len(), failed);
+    }
+    if let Some(report) = junit_report {
+        write_atomic(&report, junit_xml(&results))?;
+    }
+    if let Some(report_path) = json_report {
+        let report = conversion_report(&ctx, &results, &reports);
+        write_atomic(&report_path, serde_json::to_string_pretty(&report).unwrap())?;
+    }
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module exercising the generator and the converters.
+    const TEST_MODULE: &str = r#"
+module test {
+  yang-version 1.1;
+  namespace "urn:test";
+  prefix t;
+
+  identity base-kind;
+  identity one {
+    base base-kind;
+  }
+
+  container c {
+    leaf state {
+      type enumeration {
+        enum up;
+        enum down;
+      }
+      default up;
+    }
+    leaf motd {
+      type string;
+      default "say \"hi\" ${user}\\n";
+    }
+    leaf kind {
+      type identityref {
+        base base-kind;
+      }
+      default one;
+    }
+    leaf target {
+      type instance-identifier {
+        require-instance false;
+      }
+    }
+    leaf-list states {
+      type enumeration {
+        enum up;
+        enum down;
+      }
+    }
+    leaf address {
+      type union {
+        type string;
+        type uint8;
+      }
+    }
+  }
+}
+"#;
+
+    /// A context with the given modules loaded, from YANG sources written to
+    /// a temporary search directory.
+    fn context(modules: &[(&str, &str)]) -> Context {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = Context::new(ContextFlags::NO_YANGLIBRARY).unwrap();
+        ctx.set_searchdir(dir.path()).unwrap();
+        for (name, source) in modules {
+            std::fs::write(dir.path().join(format!("{}.yang", name)), source).unwrap();
+        }
+        for (name, _) in modules {
+            ctx.load_module(name, None, &[]).unwrap();
+        }
+        ctx
+    }
+
+    #[test]
+    fn enum_default_is_a_string_literal() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/state").unwrap();
+        assert_eq!(nix_default_literal(&node).as_deref(), Some(r#""up""#));
+    }
+
+    #[test]
+    fn string_default_is_escaped() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/motd").unwrap();
+        assert_eq!(
+            nix_default_literal(&node).as_deref(),
+            Some(r#""say \"hi\" \${user}\\n""#)
+        );
+    }
+
+    #[test]
+    fn identityref_default_is_dropped() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/kind").unwrap();
+        assert_eq!(nix_default_literal(&node), None);
+    }
+
+    #[test]
+    fn control_characters_have_no_literal() {
+        assert_eq!(nix_string_literal("a\u{7}b"), None);
+        assert_eq!(
+            nix_string_literal("$x ${y}").as_deref(),
+            Some(r#""$x \${y}""#)
+        );
+    }
+
+    #[test]
+    fn instance_identifier_is_a_checked_string() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/target").unwrap();
+        assert_eq!(
+            nix_type_for(&node, &GenerateOptions::default()).as_deref(),
+            Some(r#"(lib.types.addCheck lib.types.str (lib.hasPrefix "/"))"#)
+        );
+    }
+
+    #[test]
+    fn instance_identifier_values_are_opaque() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/target").unwrap();
+        let mut value = serde_json::json!("/test:c/state");
+        assert_eq!(coerce_value(&mut value, &node), Ok(()));
+        assert_eq!(value, "/test:c/state");
+        assert!(coerce_value(&mut serde_json::json!(1), &node).is_err());
+    }
+
+    /// Deviations of [`TEST_MODULE`], removing one leaf and making another
+    /// mandatory.
+    const DEVIATIONS_MODULE: &str = r#"
+module test-deviations {
+  yang-version 1.1;
+  namespace "urn:test-deviations";
+  prefix td;
+
+  import test {
+    prefix t;
+  }
+
+  deviation /t:c/t:motd {
+    deviate not-supported;
+  }
+  deviation /t:c/t:target {
+    deviate add {
+      mandatory true;
+    }
+  }
+}
+"#;
+
+    #[test]
+    fn not_supported_deviation_removes_the_node() {
+        let ctx = context(&[
+            ("test", TEST_MODULE),
+            ("test-deviations", DEVIATIONS_MODULE),
+        ]);
+        assert!(ctx.find_path("/test:c/motd").is_err());
+        let c = ctx.find_path("/test:c").unwrap();
+        assert!(c.children().all(|child| child.name() != "motd"));
+    }
+
+    #[test]
+    fn mandatory_deviation_makes_the_option_required() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/target").unwrap();
+        assert!(matches!(
+            leaf_optionality(&node),
+            Optionality::Nullable(None)
+        ));
+
+        let ctx = context(&[
+            ("test", TEST_MODULE),
+            ("test-deviations", DEVIATIONS_MODULE),
+        ]);
+        let node = ctx.find_path("/test:c/target").unwrap();
+        assert!(node.is_mandatory());
+        assert!(matches!(leaf_optionality(&node), Optionality::Required));
+        let option = leaf_option(&node, &GenerateOptions::default());
+        assert_eq!(option.default, None);
+        assert!(!option.nix_type.starts_with("lib.types.nullOr"));
+    }
+
+    #[test]
+    fn junit_report_has_one_case_per_file() {
+        let results = vec![
+            (PathBuf::from("ok.json"), vec![]),
+            (
+                PathBuf::from("bad<1>.json"),
+                vec![
+                    ConvertError::new("/test:c/state", "invalid value \"sideways\""),
+                    ConvertError::new("", "trailing garbage"),
+                ],
+            ),
+        ];
+        assert_eq!(
+            junit_xml(&results),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<testsuite name=\"nix-yang-tools\" tests=\"2\" failures=\"1\">\n",
+                "  <testcase name=\"ok.json\"/>\n",
+                "  <testcase name=\"bad&lt;1&gt;.json\">\n",
+                "    <failure message=\"2 errors\">/test:c/state: invalid value &quot;sideways&quot;\n",
+                "trailing garbage</failure>\n",
+                "  </testcase>\n",
+                "</testsuite>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn composite_type_arguments_are_parenthesized() {
+        assert_eq!(nix_type_arg("lib.types.str"), "lib.types.str");
+        assert_eq!(
+            nix_type_arg(r#"lib.types.enum [ "up" "down" ]"#),
+            r#"(lib.types.enum [ "up" "down" ])"#
+        );
+        assert_eq!(
+            nix_type_arg(r#"(lib.types.addCheck lib.types.str (lib.hasPrefix "/"))"#),
+            r#"(lib.types.addCheck lib.types.str (lib.hasPrefix "/"))"#
+        );
+        assert_eq!(
+            nix_type_arg("lib.types.either lib.types.str lib.types.int"),
+            "(lib.types.either lib.types.str lib.types.int)"
+        );
+        // whitespace within a string doesn't separate terms
+        assert_eq!(nix_type_arg(r#""a b""#), r#""a b""#);
+        assert_eq!(nix_type_arg(r#""a \" b""#), r#""a \" b""#);
+    }
+
+    #[test]
+    fn leaf_list_of_enums_wraps_the_enum_type() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/states").unwrap();
+        let mut options = GenerateOptions::default();
+        options.type_overrides.insert(
+            "/test:c/states".to_string(),
+            r#"lib.types.enum [ "up" "down" ]"#.to_string(),
+        );
+        assert_eq!(
+            leaf_option(&node, &options).nix_type,
+            r#"lib.types.listOf (lib.types.enum [ "up" "down" ])"#
+        );
+        assert_eq!(
+            leaf_option(&node, &GenerateOptions::default()).nix_type,
+            "lib.types.listOf lib.types.str"
+        );
+    }
+
+    #[test]
+    fn nullable_union_leaf_wraps_the_union_type() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let node = ctx.find_path("/test:c/address").unwrap();
+        let mut options = GenerateOptions::default();
+        options.type_overrides.insert(
+            "/test:c/address".to_string(),
+            "lib.types.either lib.types.str lib.types.ints.u8".to_string(),
+        );
+        assert_eq!(
+            leaf_option(&node, &options).nix_type,
+            "lib.types.nullOr (lib.types.either lib.types.str lib.types.ints.u8)"
+        );
+        assert_eq!(
+            leaf_option(&node, &GenerateOptions::default()).nix_type,
+            "lib.types.nullOr lib.types.str"
+        );
+    }
+
+    /// Leaves in and out of choices, for their optionality.
+    const CHOICE_MODULE: &str = r#"
+module choices {
+  yang-version 1.1;
+  namespace "urn:choices";
+  prefix ch;
+
+  container c {
+    leaf plain {
+      type string;
+    }
+    leaf defaulted {
+      type uint16;
+      default 22;
+    }
+    leaf required {
+      type string;
+      mandatory true;
+    }
+    choice transport {
+      case tcp {
+        leaf host {
+          type string;
+          mandatory true;
+        }
+        leaf port {
+          type uint16;
+        }
+      }
+    }
+    choice mode {
+      mandatory true;
+      case active {
+        leaf interval {
+          type uint32;
+          mandatory true;
+        }
+      }
+    }
+  }
+  list peer {
+    key name;
+    leaf name {
+      type string;
+    }
+    choice auth {
+      case password {
+        leaf user {
+          type string;
+          mandatory true;
+        }
+        leaf password {
+          type string;
+        }
+      }
+    }
+  }
+}
+"#;
+
+    #[test]
+    fn leaf_optionality_follows_defaults_and_choices() {
+        let ctx = context(&[("choices", CHOICE_MODULE)]);
+        let cases = [
+            ("/choices:c/plain", Optionality::Nullable(None)),
+            (
+                "/choices:c/defaulted",
+                Optionality::Default("22".to_string()),
+            ),
+            ("/choices:c/required", Optionality::Required),
+            (
+                "/choices:c/host",
+                Optionality::Nullable(Some("tcp".to_string())),
+            ),
+            ("/choices:c/port", Optionality::Nullable(None)),
+            ("/choices:c/interval", Optionality::Required),
+        ];
+        for (path, optionality) in cases {
+            let node = ctx.find_path(path).unwrap();
+            assert_eq!(leaf_optionality(&node), optionality, "{}", path);
+        }
+    }
+
+    #[test]
+    fn home_manager_module_asserts_case_mandatory_leaves() {
+        let ctx = context(&[("choices", CHOICE_MODULE)]);
+        let module = ctx.get_module_latest("choices").unwrap();
+        let options = GenerateOptions::default();
+        let mut readme = None;
+        let mut builder = NixOptionsBuilder::home_manager(&options, &mut readme, "choices");
+        for root in module.data() {
+            builder.add_node(root);
+        }
+        let nix = builder.finish();
+        let assertions = nix
+            .split_once("  config.assertions = [\n")
+            .and_then(|(_, rest)| rest.split_once("  ];\n"))
+            .map(|(assertions, _)| assertions.lines().collect::<Vec<_>>());
+        assert_eq!(
+            assertions,
+            Some(vec![
+                "    { assertion = (cfg.c.transport.tcp.port != null) -> \
+                 cfg.c.transport.tcp.host != null; message = \
+                 \"/choices:c/host must be set when other options of case tcp are set\"; }",
+                "    { assertion = lib.all (entry: (entry.auth.password.password != null) -> \
+                 entry.auth.password.user != null) (lib.attrValues cfg.peer); message = \
+                 \"/choices:peer/user must be set when other options of case password are set\"; }",
+            ])
+        );
+        assert!(nix.ends_with("];\n}\n"));
+
+        let mut readme = None;
+        let mut builder = NixOptionsBuilder::new(&options, &mut readme);
+        builder.add_node(ctx.find_path("/choices:c").unwrap());
+        assert!(!builder.finish().contains("assertions"));
+    }
+
+    #[test]
+    fn generated_files_are_byte_stable() {
+        let generate = || {
+            let ctx = context(&[("test", TEST_MODULE), ("choices", CHOICE_MODULE)]);
+            let options = GenerateOptions {
+                warn_on_todo_types: true,
+                ..Default::default()
+            };
+            let roots = || {
+                ["test", "choices"]
+                    .into_iter()
+                    .flat_map(|name| ctx.get_module_latest(name).unwrap().data())
+            };
+            let nix = nix_options_file(roots(), &options, &mut None);
+            (nix, nixos_test(roots(), &options))
+        };
+        assert_eq!(generate(), generate());
+    }
+
+    #[test]
+    fn report_snippets_are_capped() {
+        let short = serde_json::json!({ "a": [1, 2] });
+        assert_eq!(ReportItem::snippet(&short), r#"{"a":[1,2]}"#);
+        let long = serde_json::Value::from(vec!["ä"; 1000]);
+        let snippet = ReportItem::snippet(&long);
+        assert_eq!(snippet.chars().count(), 201);
+        assert!(snippet.starts_with(r#"["ä","ä""#));
+        assert!(snippet.ends_with('…'));
+    }
+
+    #[test]
+    fn disabled_report_builds_no_items() {
+        let mut report = Report::default();
+        report.push(|| unreachable!());
+        assert!(report.items().is_empty());
+    }
+
+    #[test]
+    fn conversion_report_counts_actions_per_file() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let mut report = Report::new(true);
+        report.push(|| ReportItem::new("/test:c/state", "warning").message("unknown value"));
+        report.push(|| {
+            ReportItem::new("/test:c/motd", "coerce")
+                .before(&serde_json::json!(1))
+                .after(&serde_json::json!("1"))
+        });
+        let results = vec![(
+            PathBuf::from("in.json"),
+            vec![ConvertError::new("/test:c", "failed")],
+        )];
+        let json = conversion_report(&ctx, &results, &[report]);
+        assert_eq!(json["tool"]["name"], env!("CARGO_PKG_NAME"));
+        assert!(json["schema"]["fingerprint"].is_string());
+        let file = &json["files"][0];
+        assert_eq!(file["file"], "in.json");
+        assert_eq!(
+            file["errors"],
+            serde_json::json!([{ "path": "/test:c", "message": "failed" }])
+        );
+        assert_eq!(
+            file["counters"],
+            serde_json::json!({ "coerce": 1, "warning": 1 })
+        );
+        assert_eq!(
+            file["items"][1],
+            serde_json::json!({
+                "path": "/test:c/motd",
+                "action": "coerce",
+                "message": null,
+                "before": "1",
+                "after": "\"1\"",
+            })
+        );
+    }
+
+    #[test]
+    fn schema_path_drops_predicates() {
+        assert_eq!(
+            schema_path(r#"/m:l[name='a]b'][id="x'y"]/m:c/m:ll[.='1']"#),
+            "/m:l/m:c/m:ll"
+        );
+    }
+
+    #[test]
+    fn integer_and_boolean_types_are_mapped() {
+        let ctx = context(&[(
+            "types",
+            r#"
+module types {
+  yang-version 1.1;
+  namespace "urn:types";
+  prefix ty;
+
+  leaf flag { type boolean; }
+  leaf small { type int16; }
+  leaf medium { type int32; }
+  leaf large { type int64; }
+}
+"#,
+        )]);
+        let options = GenerateOptions::default();
+        let cases = [
+            ("/types:flag", "lib.types.bool"),
+            ("/types:small", "lib.types.ints.s16"),
+            ("/types:medium", "lib.types.ints.s32"),
+            (
+                "/types:large",
+                r#"(lib.types.addCheck lib.types.str (s: builtins.match "-?[0-9]+" s != null))"#,
+            ),
+        ];
+        for (path, nix_type) in cases {
+            let node = ctx.find_path(path).unwrap();
+            assert_eq!(
+                nix_type_for(&node, &options).as_deref(),
+                Some(nix_type),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn nix_value_renders_nested_json() {
+        let value = serde_json::json!({
+            "a-b": [1, -2, "x${y}"],
+            "1st": { "empty": [], "none": null, "obj": {} },
+            "ok": true,
+        });
+        assert_eq!(
+            nix_value(&value, "", false),
+            concat!(
+                "{\n",
+                "  \"1st\" = {\n",
+                "    empty = [ ];\n",
+                "    none = null;\n",
+                "    obj = { };\n",
+                "  };\n",
+                "  a-b = [\n",
+                "    1\n",
+                "    (-2)\n",
+                "    \"x\\${y}\"\n",
+                "  ];\n",
+                "  ok = true;\n",
+                "}",
+            )
+        );
+        assert_eq!(
+            nix_value(&serde_json::json!("grüße"), "", true),
+            nix_string("grüße", true)
+        );
+    }
+
+    #[test]
+    fn nix_overlay_keeps_changes_and_collects_removals() {
+        // what the module system makes of the overlay: attrsets are merged,
+        // other values replaced, and the removals are dropped
+        fn apply(
+            base: &serde_json::Value,
+            overlay: &serde_json::Value,
+            removals: &[Vec<String>],
+        ) -> serde_json::Value {
+            fn merge(base: &mut serde_json::Value, overlay: &serde_json::Value) {
+                match (base, overlay) {
+                    (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+                        for (key, value) in overlay {
+                            match base.get_mut(key) {
+                                Some(member) => merge(member, value),
+                                None => drop(base.insert(key.clone(), value.clone())),
+                            }
+                        }
+                    }
+                    (base, overlay) => *base = overlay.clone(),
+                }
+            }
+            let mut merged = base.clone();
+            merge(&mut merged, overlay);
+            for path in removals {
+                let (last, parents) = path.split_last().unwrap();
+                let parent = parents
+                    .iter()
+                    .try_fold(&mut merged, |value, key| value.get_mut(key));
+                if let Some(serde_json::Value::Object(parent)) = parent {
+                    parent.remove(last);
+                }
+            }
+            merged
+        }
+
+        let left = serde_json::json!({
+            "same": { "a": 1 },
+            "changed": { "a": 1, "b": 2, "gone": 3 },
+            "list": [1, 2],
+            "dropped": {},
+        });
+        let right = serde_json::json!({
+            "same": { "a": 1 },
+            "changed": { "a": 1, "b": 20, "new": 4 },
+            "list": [1, 2, 3],
+        });
+        let mut removals = vec![];
+        let overlay = nix_overlay(&NixDoc(left.clone()), &NixDoc(right.clone()), &mut removals);
+        assert_eq!(
+            overlay,
+            Some(serde_json::json!({
+                "changed": { "b": 20, "new": 4 },
+                "list": [1, 2, 3],
+            }))
+        );
+        assert_eq!(
+            removals,
+            vec![
+                vec!["dropped".to_string()],
+                vec!["changed".to_string(), "gone".to_string()],
+            ]
+        );
+        assert_eq!(apply(&left, &overlay.unwrap(), &removals), right);
+
+        let mut removals = vec![];
+        let left = NixDoc(left);
+        assert_eq!(nix_overlay(&left, &left, &mut removals), None);
+        assert!(removals.is_empty());
+    }
+
+    #[test]
+    fn whitespace_normalization_merges_entries_in_order() {
+        let ctx = context(&[(
+            "ws",
+            r#"
+module ws {
+  yang-version 1.1;
+  namespace "urn:ws";
+  prefix ws;
+
+  list user {
+    key name;
+    leaf name { type string; }
+    leaf shell { type string; }
+    leaf uid { type uint32; }
+  }
+  leaf-list search {
+    type string;
+    ordered-by user;
+  }
+  leaf motd { type string; }
+}
+"#,
+        )]);
+        let top_level = data_children(ctx.get_module_latest("ws").unwrap().data());
+        let mut data = YangDoc(serde_json::json!({
+            "ws:user": [
+                { "name": "b", "uid": 2 },
+                { "name": " a", "shell": "sh\r\n" },
+                { "name": "a\r\n", "uid": 1, "shell": "bash" },
+            ],
+            "ws:search": ["z ", "y", "z", " x\r"],
+            "ws:motd": "  hello\r\nworld \r",
+        }));
+        normalize_whitespace(&mut data, &top_level);
+        assert_eq!(
+            data.0,
+            serde_json::json!({
+                "ws:user": [
+                    { "name": "b", "uid": 2 },
+                    { "name": "a", "shell": "sh", "uid": 1 },
+                ],
+                "ws:search": ["z", "y", "x"],
+                "ws:motd": "hello\nworld",
+            })
+        );
+    }
+
+    /// A list with three keys of different types.
+    const PORTS_MODULE: &str = r#"
+module ports {
+  yang-version 1.1;
+  namespace "urn:ports";
+  prefix p;
+
+  list port {
+    key "name slot vlan";
+    leaf name { type string; }
+    leaf slot { type uint8; }
+    leaf vlan { type uint16; }
+    leaf mtu { type uint16; }
+  }
+}
+"#;
+
+    #[test]
+    fn nesting_levels_hold_keys_in_schema_order() {
+        let ctx = context(&[("ports", PORTS_MODULE)]);
+        let node = ctx.find_path("/ports:port").unwrap();
+        let keys = ConvertOptions::default().key_order(&node).unwrap();
+        assert_eq!(
+            keys.iter().map(|key| key.name()).collect::<Vec<_>>(),
+            ["name", "slot", "vlan"]
+        );
+
+        let mut e = serde_json::json!({ "eth0": { "1": { "100": { "mtu": 1500 } } } });
+        let mut errors = vec![];
+        nix_list_to_yang(&mut e, "/port", &keys, NormalizeKeys::None, &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            e,
+            serde_json::json!([{ "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 }])
+        );
+    }
+
+    #[test]
+    fn misordered_nesting_names_the_mismatched_level() {
+        let ctx = context(&[("ports", PORTS_MODULE)]);
+        let node = ctx.find_path("/ports:port").unwrap();
+        let keys = ConvertOptions::default().key_order(&node).unwrap();
+
+        let mut e = serde_json::json!({ "1": { "eth0": { "100": {} } } });
+        let mut errors = vec![];
+        nix_list_to_yang(&mut e, "/port", &keys, NormalizeKeys::None, &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/port/1/eth0/100");
+        assert!(
+            errors[0]
+                .message
+                .starts_with("nesting level 2 holds key \"slot\""),
+            "{}",
+            errors[0].message
+        );
+        assert!(errors[0].message.contains("levels are keys name,slot,vlan"));
+    }
+
+    #[test]
+    fn key_order_override_reorders_levels() {
+        let ctx = context(&[("ports", PORTS_MODULE)]);
+        let node = ctx.find_path("/ports:port").unwrap();
+        let mut options = ConvertOptions::default();
+        let order = ["slot", "name", "vlan"].map(String::from).to_vec();
+        options.key_orders.insert("/ports:port".to_string(), order);
+        let keys = options.key_order(&node).unwrap();
+
+        let mut e = serde_json::json!({ "1": { "eth0": { "100": {} } } });
+        let mut errors = vec![];
+        nix_list_to_yang(&mut e, "/port", &keys, NormalizeKeys::None, &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            e,
+            serde_json::json!([{ "name": "eth0", "slot": 1, "vlan": 100 }])
+        );
+
+        options
+            .key_orders
+            .insert("/ports:port".to_string(), vec!["slot".to_string()]);
+        assert!(options.key_order(&node).is_err());
+    }
+
+    #[test]
+    fn documents_are_detected_by_their_first_keyed_list() {
+        let ctx = context(&[("ports", PORTS_MODULE)]);
+        let top_level = data_children(ctx.get_module_latest("ports").unwrap().data());
+        let yang = serde_json::json!({ "ports:port": [{ "name": "eth0", "slot": 1, "vlan": 1 }] });
+        assert!(matches!(Doc::detect(yang, &top_level), Ok(Doc::Yang(_))));
+        let nix = serde_json::json!({ "ports:port": { "eth0": { "1": { "1": {} } } } });
+        assert!(matches!(Doc::detect(nix, &top_level), Ok(Doc::Nix(_))));
+        let neither = serde_json::json!({ "ports:other": 1 });
+        assert!(matches!(Doc::detect(neither.clone(), &top_level), Err(value) if value == neither));
+
+        // the wrappers serialize as the documents themselves
+        let text = r#"{"ports:port":[{"name":"eth0","slot":1,"vlan":1}]}"#;
+        let yang: YangDoc = serde_json::from_str(text).unwrap();
+        assert_eq!(serde_json::to_string(&yang).unwrap(), text);
+        let doc = Doc::detect(yang.0, &top_level).unwrap();
+        assert_eq!(doc.mode(), ConvertMode::Yang2Nix);
+        assert_eq!(serde_json::to_string(&doc).unwrap(), text);
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn lcs_diff_marks_common_and_changed_items() {
+        let a = ["a", "b", "c", "d"];
+        let b = ["a", "c", "x", "d"];
+        let diff = lcs_diff(&a, &b)
+            .into_iter()
+            .map(|(op, item)| format!("{}{}", op, item))
+            .collect::<Vec<_>>();
+        assert_eq!(diff, [" a", "-b", " c", "+x", " d"]);
+        assert!(lcs_diff::<&str>(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn ordered_leaf_list_changes_are_positional() {
+        let old = strings(&["a", "b", "c"]);
+        let new = strings(&["c", "a", "b"]);
+        assert_eq!(
+            value_changes(&old, &new, true),
+            [('+', "c".to_string()), ('-', "c".to_string())]
+        );
+        assert!(value_changes(&old, &old, true).is_empty());
+    }
+
+    #[test]
+    fn unordered_leaf_list_changes_are_a_set_difference() {
+        let old = strings(&["a", "b", "c"]);
+        let new = strings(&["c", "a", "d"]);
+        assert_eq!(
+            value_changes(&old, &new, false),
+            [('-', "b".to_string()), ('+', "d".to_string())]
+        );
+        assert!(value_changes(&old, &strings(&["c", "b", "a"]), false).is_empty());
+    }
+
+    #[test]
+    fn long_ordered_leaf_lists_are_replaced_as_a_whole() {
+        let n = 4000;
+        let old = (0..n).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut new = old.clone();
+        new.reverse();
+        let changes = value_changes(&old, &new, true);
+        assert_eq!(changes.len(), 2 * n);
+        assert!(changes[..n].iter().all(|(op, _)| *op == '-'));
+        assert!(changes[n..].iter().all(|(op, _)| *op == '+'));
+
+        // the common ends aren't part of the replacement
+        let mut new = old.clone();
+        new[1] = "x".to_string();
+        assert_eq!(
+            value_changes(&old, &new, true),
+            [('-', "1".to_string()), ('+', "x".to_string())]
+        );
+    }
+
+    #[test]
+    fn sha256_matches_the_nist_vectors() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // 448 bits, so that the padding takes a second block
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256_hex(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn timestamps_cross_leap_days_and_year_ends() {
+        let cases = [
+            (0, "1970-01-01T00:00:00Z"),
+            (951827696, "2000-02-29T12:34:56Z"),
+            (1704067199, "2023-12-31T23:59:59Z"),
+            (1704067200, "2024-01-01T00:00:00Z"),
+            (1709164799, "2024-02-28T23:59:59Z"),
+            (1709164800, "2024-02-29T00:00:00Z"),
+            (1709251200, "2024-03-01T00:00:00Z"),
+            // 2100 isn't a leap year
+            (4107542400, "2100-03-01T00:00:00Z"),
+        ];
+        for (time, timestamp) in cases {
+            assert_eq!(rfc3339_utc(time), timestamp);
+        }
+    }
+
+    #[test]
+    fn namespace_prefixes_are_stripped_from_names_only() {
+        let cases = [
+            (
+                "/rtbrick-config:config/interface[name='eth0']/ip-address",
+                "/config/interface[name='eth0']/ip-address",
+            ),
+            (
+                "/a:x/b:y[b:name='p:q/r:s'][.=\"t:u\"]/a:z",
+                "/x/y[name='p:q/r:s'][.=\"t:u\"]/z",
+            ),
+            ("/unqualified/path", "/unqualified/path"),
+        ];
+        for (path, stripped) in cases {
+            assert_eq!(strip_namespace_prefixes(path), stripped);
+        }
+    }
+
+    #[test]
+    fn member_prefixes_are_stripped_but_not_values() {
+        let mut value = serde_json::json!({
+            "m:c": { "kind": "m:one", "m:l": [{ "o:name": "x" }] },
+        });
+        strip_member_prefixes(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({ "c": { "kind": "m:one", "l": [{ "name": "x" }] } })
+        );
+    }
+
+    #[test]
+    fn shorthand_cases_are_not_nested() {
+        let ctx = context(&[(
+            "shorthand",
+            r#"
+module shorthand {
+  yang-version 1.1;
+  namespace "urn:shorthand";
+  prefix sh;
+
+  container c {
+    choice proto {
+      leaf udp { type uint16; }
+      container tcp {
+        leaf port { type uint16; }
+      }
+      case sctp {
+        leaf sctp-port { type uint16; }
+        leaf streams { type uint16; }
+      }
+    }
+  }
+}
+"#,
+        )]);
+        let c = ctx.find_path("/shorthand:c").unwrap();
+        let proto = c.children().find(|child| child.name() == "proto").unwrap();
+        let cases = proto
+            .children()
+            .map(|case| (case.name().to_string(), is_shorthand_case(&case)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cases,
+            [
+                ("udp".to_string(), true),
+                ("tcp".to_string(), true),
+                ("sctp".to_string(), false),
+            ]
+        );
+
+        let nix = nix_options_file(std::iter::once(c), &GenerateOptions::default(), &mut None);
+        assert!(nix.contains("proto = {"));
+        assert!(nix.contains("sctp = {"));
+        assert!(nix.contains("udp = lib.mkOption"));
+        assert!(!nix.contains("udp = {"));
+        assert_eq!(nix.matches("tcp = {").count(), 1);
+    }
+
+    #[test]
+    fn members_renamed_to_the_same_name_are_reported() {
+        let ctx = context(&[(
+            "camel",
+            r#"
+module camel {
+  yang-version 1.1;
+  namespace "urn:camel";
+  prefix cm;
+
+  container c {
+    leaf foo-bar { type string; }
+    leaf fooBar { type string; }
+  }
+}
+"#,
+        )]);
+        let top_level = data_children(ctx.get_module_latest("camel").unwrap().data());
+        let options = ConvertOptions {
+            auto_camelcase: true,
+            ..Default::default()
+        };
+        let mut value = serde_json::json!({ "c": { "foo-bar": "a", "fooBar": "b" } });
+        let mut errors = vec![];
+        rename_members(
+            &mut value,
+            "",
+            None,
+            &top_level,
+            &options,
+            true,
+            &mut errors,
+        );
+        assert_eq!(value, serde_json::json!({ "c": { "fooBar": "a" } }));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/c/fooBar");
+        assert_eq!(
+            errors[0].message,
+            "members foo-bar and fooBar are both renamed to fooBar"
+        );
+    }
+
+    #[test]
+    fn stripped_prefixes_of_augments_round_trip() {
+        let augment = |name: &str, leaves: &str| {
+            format!(
+                "module {name} {{ yang-version 1.1; namespace \"urn:{name}\"; prefix {name}; \
+                 import test {{ prefix t; }} augment \"/t:c\" {{ {leaves} }} }}"
+            )
+        };
+        let aug = augment(
+            "aug",
+            "leaf extra { type string; } leaf note { type string; }",
+        );
+        let aug2 = augment("aug2", "leaf extra { type string; }");
+        let ctx = Arc::new(context(&[
+            ("test", TEST_MODULE),
+            ("aug", &aug),
+            ("aug2", &aug2),
+        ]));
+        let options = ConvertOptions {
+            strip_prefixes: true,
+            ..Default::default()
+        };
+        let yang = serde_json::json!({
+            "test:c": { "state": "down", "aug:extra": "x", "aug2:extra": "y", "aug:note": "n" },
+        });
+        let path = Path::new("augmented.json");
+        let mut report = Report::default();
+        let doc = YangDoc(yang.clone());
+        let nix = yang_to_nix(&ctx, path, doc, &options, &mut report).unwrap();
+        assert_eq!(
+            nix.0,
+            serde_json::json!({
+                "c": { "state": "down", "aug:extra": "x", "aug2:extra": "y", "note": "n" },
+            })
+        );
+        let back = nix_to_yang(&ctx, path, nix, &options, &mut report).unwrap();
+        assert_eq!(back.into_inner(), yang);
+    }
+
+    #[test]
+    fn list_keys_are_never_nullable() {
+        let ctx = context(&[("choices", CHOICE_MODULE), ("ports", PORTS_MODULE)]);
+        let opts = GenerateOptions::default();
+        let cases = [
+            ("/choices:peer/name", "lib.types.str"),
+            ("/ports:port/name", "lib.types.str"),
+            ("/ports:port/slot", "lib.types.ints.u8"),
+            ("/ports:port/vlan", "lib.types.ints.u16"),
+        ];
+        for (path, nix_type) in cases {
+            let node = ctx.find_path(path).unwrap();
+            assert_eq!(leaf_optionality(&node), Optionality::Required, "{}", path);
+            let option = leaf_option(&node, &opts);
+            assert_eq!(option.nix_type, nix_type, "{}", path);
+            assert_eq!(option.default, None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn leaf_values_are_coerced_by_type() {
+        let ctx = context(&[(
+            "coerce",
+            r#"
+module coerce {
+  yang-version 1.1;
+  namespace "urn:coerce";
+  prefix co;
+
+  identity base-kind;
+  identity one {
+    base base-kind;
+  }
+
+  leaf s8 { type int8; }
+  leaf u8 { type uint8; }
+  leaf s16 { type int16; }
+  leaf u16 { type uint16; }
+  leaf s32 { type int32; }
+  leaf u32 { type uint32; }
+  leaf s64 { type int64; }
+  leaf u64 { type uint64; }
+  leaf dec { type decimal64 { fraction-digits 2; } }
+  leaf flag { type boolean; }
+  leaf marker { type empty; }
+  leaf state {
+    type enumeration {
+      enum true;
+      enum false;
+      enum up;
+    }
+  }
+  leaf kind { type identityref { base base-kind; } }
+  leaf text { type string; }
+}
+"#,
+        )]);
+        use serde_json::json;
+        let cases = [
+            ("s8", json!("-5"), Some(json!(-5))),
+            ("s8", json!(5), Some(json!(5))),
+            ("s8", json!("x"), None),
+            ("u8", json!("255"), Some(json!(255))),
+            ("s16", json!("-300"), Some(json!(-300))),
+            ("u16", json!("8080"), Some(json!(8080))),
+            ("s32", json!("-70000"), Some(json!(-70000))),
+            ("u32", json!("4294967295"), Some(json!(4294967295u32))),
+            ("u32", json!(true), None),
+            ("s64", json!(i64::MIN), Some(json!("-9223372036854775808"))),
+            (
+                "s64",
+                json!("-9223372036854775808"),
+                Some(json!("-9223372036854775808")),
+            ),
+            ("s64", json!("1.5"), None),
+            ("u64", json!(u64::MAX), Some(json!("18446744073709551615"))),
+            (
+                "u64",
+                json!("18446744073709551615"),
+                Some(json!("18446744073709551615")),
+            ),
+            ("u64", json!(-1), None),
+            ("dec", json!(1.5), Some(json!("1.5"))),
+            ("dec", json!("2.25"), Some(json!("2.25"))),
+            ("dec", json!("abc"), None),
+            ("flag", json!("true"), Some(json!(true))),
+            ("flag", json!(false), Some(json!(false))),
+            ("flag", json!("yes"), None),
+            ("marker", json!(true), Some(json!([null]))),
+            ("marker", json!([null]), Some(json!([null]))),
+            ("marker", json!(false), None),
+            ("state", json!(true), Some(json!("true"))),
+            ("state", json!("up"), Some(json!("up"))),
+            ("state", json!(5), Some(json!("5"))),
+            ("kind", json!("one"), Some(json!("one"))),
+            ("kind", json!(1), None),
+            ("text", json!(42), Some(json!("42"))),
+            ("text", json!(null), Some(json!(null))),
+        ];
+        for (leaf, value, expected) in cases {
+            let node = ctx.find_path(&format!("/coerce:{}", leaf)).unwrap();
+            let mut coerced = value.clone();
+            match (coerce_value(&mut coerced, &node), expected) {
+                (Ok(()), Some(expected)) => assert_eq!(coerced, expected, "{} {}", leaf, value),
+                (Err(_), None) => assert_eq!(coerced, value, "{} {}", leaf, value),
+                (result, _) => panic!("{} {}: {:?}", leaf, value, result),
+            }
+        }
+
+        let node = ctx.find_path("/coerce:s8").unwrap();
+        let message = coerce_value(&mut json!("x"), &node).unwrap_err();
+        assert_eq!(message, "expected Int8, found \"x\"");
+    }
+
+    #[test]
+    fn imports_resolve_across_search_directories() {
+        let vendor = tempfile::tempdir().unwrap();
+        let ietf = tempfile::tempdir().unwrap();
+        std::fs::write(
+            vendor.path().join("vendor.yang"),
+            "module vendor { yang-version 1.1; namespace \"urn:vendor\"; prefix v; \
+             import base { prefix b; } container c { leaf x { type b:name; } } }",
+        )
+        .unwrap();
+        let nested = ietf.path().join("standard").join("ietf");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(
+            nested.join("base.yang"),
+            "module base { yang-version 1.1; namespace \"urn:base\"; prefix b; \
+             typedef name { type string; } }",
+        )
+        .unwrap();
+
+        let mut search_dirs = vec![vendor.path().to_path_buf(), ietf.path().to_path_buf()];
+        let load = |search_dirs: &[PathBuf]| {
+            let mut ctx = Context::new(ContextFlags::NO_YANGLIBRARY).unwrap();
+            for dir in search_dirs {
+                ctx.set_searchdir(dir).unwrap();
+            }
+            let loaded = ctx.load_module("vendor", None, &[]).is_ok();
+            loaded.then_some(ctx)
+        };
+        assert!(load(&search_dirs).is_none());
+
+        let mut subdirs = vec![];
+        for dir in &search_dirs {
+            subdirectories(dir, &mut subdirs).unwrap();
+        }
+        assert_eq!(subdirs, [ietf.path().join("standard"), nested.clone()]);
+        search_dirs.extend(subdirs);
+        let ctx = load(&search_dirs).unwrap();
+        assert!(ctx.get_module_latest("base").is_some());
+        assert!(ctx.find_path("/vendor:c/x").is_ok());
+
+        let missing = vendor.path().join("missing");
+        assert!(subdirectories(&missing, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn largest_uint64_survives_a_round_trip() {
+        let ctx = Arc::new(context(&[(
+            "counters",
+            "module counters { yang-version 1.1; namespace \"urn:counters\"; prefix cn; \
+             container stats { leaf octets { type uint64; } } }",
+        )]));
+        let options = ConvertOptions::default();
+        let path = Path::new("counters.json");
+        let mut report = Report::default();
+        let yang = serde_json::json!({ "counters:stats": { "octets": "18446744073709551615" } });
+        let doc = YangDoc(yang.clone());
+        let nix = yang_to_nix(&ctx, path, doc, &options, &mut report).unwrap();
+        assert_eq!(nix.0, yang);
+        let back = nix_to_yang(&ctx, path, nix, &options, &mut report).unwrap();
+        assert_eq!(back.into_inner(), yang);
+
+        // nix2yang also takes the integer forms
+        for octets in [serde_json::json!(u64::MAX), serde_json::json!(5)] {
+            let doc = NixDoc(serde_json::json!({ "counters:stats": { "octets": octets } }));
+            let yang = nix_to_yang(&ctx, path, doc, &options, &mut report).unwrap();
+            let expected =
+                serde_json::json!({ "counters:stats": { "octets": octets.to_string() } });
+            assert_eq!(yang.into_inner(), expected);
+        }
+    }
+
+    #[test]
+    fn members_of_unloaded_modules_fail_or_are_skipped() {
+        let ctx = Arc::new(context(&[("test", TEST_MODULE)]));
+        let nix = serde_json::json!({
+            "c": { "state": "up", "vendor:x": 1, "vendor:y": { "z": 1 } },
+            "other:top": {},
+        });
+        let path = Path::new("unknown.json");
+        let mut report = Report::default();
+
+        let options = ConvertOptions::default();
+        let doc = NixDoc(nix.clone());
+        let errors = nix_to_yang(&ctx, path, doc, &options, &mut report).unwrap_err();
+        let errors = errors
+            .iter()
+            .map(|error| (error.path.as_str(), error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                (
+                    "/other:top",
+                    "module other is not loaded (used by 1 members), add it with --import"
+                ),
+                (
+                    "/c/vendor:x",
+                    "module vendor is not loaded (used by 2 members), add it with --import"
+                ),
+            ]
+        );
+
+        let options = ConvertOptions {
+            skip_unknown_modules: true,
+            ..Default::default()
+        };
+        let yang = nix_to_yang(&ctx, path, NixDoc(nix), &options, &mut report).unwrap();
+        assert_eq!(
+            yang.into_inner(),
+            serde_json::json!({ "test:c": { "state": "up" } })
+        );
+    }
+
+    #[test]
+    fn kept_keys_round_trip_and_must_name_their_entry() {
+        use serde_json::json;
+        let ctx = context(&[("ports", PORTS_MODULE)]);
+        let node = ctx.find_path("/ports:port").unwrap();
+        let keys = ConvertOptions::default().key_order(&node).unwrap();
+        let yang = json!([{ "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 }]);
+        let mut report = Report::default();
+
+        let mut errors = vec![];
+        let mut e = yang.clone();
+        let options = ConvertOptions::default();
+        yang_list_to_nix(&mut e, "/port", &keys, &options, &mut errors, &mut report);
+        assert_eq!(e, json!({ "eth0": { "1": { "100": { "mtu": 1500 } } } }));
+
+        let mut e = yang.clone();
+        let options = ConvertOptions {
+            keep_keys: true,
+            ..Default::default()
+        };
+        yang_list_to_nix(&mut e, "/port", &keys, &options, &mut errors, &mut report);
+        assert_eq!(
+            e,
+            json!({ "eth0": { "1": { "100": { "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 } } } })
+        );
+        nix_list_to_yang(&mut e, "/port", &keys, NormalizeKeys::None, &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(e, yang);
+
+        let mismatches = [
+            (
+                json!({ "name": "eth1", "mtu": 1500 }),
+                "/port/eth0/1/100/name",
+                "key \"name\" is \"eth1\" but the entry is named \"eth0\"",
+            ),
+            (
+                json!({ "slot": 2, "mtu": 1500 }),
+                "/port/eth0/1/100/slot",
+                "key \"slot\" is 2 but the entry is named 1",
+            ),
+        ];
+        for (entry, path, message) in mismatches {
+            let mut e = json!({ "eth0": { "1": { "100": entry } } });
+            let mut errors = vec![];
+            nix_list_to_yang(&mut e, "/port", &keys, NormalizeKeys::None, &mut errors);
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            assert_eq!(errors[0].path, path);
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn origin_filter_keeps_nodes_of_the_given_origins() {
+        let mut dump = serde_json::json!({
+            "test:c": {
+                "@": { "ietf-origin:origin": "ietf-origin:intended" },
+                "state": "up",
+                "motd": "hi",
+                "@motd": { "ietf-origin:origin": "ietf-origin:system" },
+                "states": ["up", "down"],
+                "@states": [{ "ietf-origin:origin": "ietf-origin:learned" }, null],
+            },
+            "other:c": { "x": 1 },
+            "other:loose": 5,
+        });
+        let origins = strings(&["intended"]);
+        assert!(filter_origin(&mut dump, None, &origins));
+        assert_eq!(
+            dump,
+            serde_json::json!({ "test:c": { "state": "up", "states": ["down"] } })
+        );
+
+        let mut dump = serde_json::json!({ "other:c": { "x": 1 } });
+        assert!(!filter_origin(&mut dump, None, &origins));
+        assert_eq!(dump, serde_json::json!({}));
+    }
+
+    #[test]
+    fn grep_matches_names_and_values() {
+        let ctx = Arc::new(context(&[("test", TEST_MODULE)]));
+        let data = parse_data_string(
+            &ctx,
+            Path::new("grep.json"),
+            r#"{ "test:c": { "state": "down", "motd": "Status ok" } }"#,
+            &ParseOptions::default(),
+        );
+        let pattern = regex::RegexBuilder::new("stat")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        let state = "schema\t/test:c/state\tleaf\tEnum";
+        let states = "schema\t/test:c/states\tleaflist\tEnum";
+        let motd = "data\t/test:c/motd\tStatus ok";
+        let cases = [
+            (GrepOptions::default(), vec![state, states, motd]),
+            (
+                GrepOptions {
+                    names_only: true,
+                    ..Default::default()
+                },
+                vec![state, states],
+            ),
+            (
+                GrepOptions {
+                    values_only: true,
+                    ..Default::default()
+                },
+                vec![motd],
+            ),
+            (
+                GrepOptions {
+                    limit: Some(1),
+                    ..Default::default()
+                },
+                vec![state],
+            ),
+        ];
+        for (options, lines) in cases {
+            assert_eq!(grep(&ctx, &pattern, Some(&data), &options), lines);
+        }
+        assert_eq!(
+            grep(&ctx, &pattern, None, &GrepOptions::default()),
+            [state, states]
+        );
+    }
+
+    #[test]
+    fn envelopes_are_unwrapped_and_rewrapped() {
+        use serde_json::json;
+        let ctx = Arc::new(context(&[("ports", PORTS_MODULE)]));
+        let path = Path::new("envelope.json");
+        let ports = json!([{ "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 }]);
+        let nix_ports = json!({ "eth0": { "1": { "100": { "mtu": 1500 } } } });
+
+        let mut report = Report::new(true);
+        let doc = YangDoc(json!({ "ietf-restconf:data": { "ports:port": ports } }));
+        let options = ConvertOptions::default();
+        let nix = yang_to_nix(&ctx, path, doc, &options, &mut report).unwrap();
+        assert_eq!(
+            nix.into_inner(),
+            json!({ "ietf-restconf:data": { "ports:port": nix_ports } })
+        );
+        assert_eq!(report.items()[0].path, "/ietf-restconf:data");
+        assert_eq!(report.items()[0].action, "unwrap");
+
+        let doc = YangDoc(json!({ "device:state": { "ports:port": ports } }));
+        let options = ConvertOptions {
+            unwrap: strings(&["device:state"]),
+            no_rewrap: true,
+            ..Default::default()
+        };
+        let nix = yang_to_nix(&ctx, path, doc, &options, &mut report).unwrap();
+        assert_eq!(nix.into_inner(), json!({ "ports:port": nix_ports }));
+
+        let mut report = Report::new(true);
+        let unknown = json!({ "device:state": { "ports:port": ports } });
+        let doc = YangDoc(unknown.clone());
+        let options = ConvertOptions::default();
+        let nix = yang_to_nix(&ctx, path, doc, &options, &mut report).unwrap();
+        assert_eq!(nix.into_inner(), unknown);
+        let items = report.items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].path, "/device:state");
+        assert_eq!(items[0].action, "warning");
+        assert_eq!(
+            items[0].message.as_deref(),
+            Some("not a schema node, if it is an envelope try --unwrap device:state")
+        );
+    }
+
+    const PATCH_MODULE: &str = r#"
+module patch {
+  yang-version 1.1;
+  namespace "urn:patch";
+  prefix pt;
+
+  container c {
+    leaf name { type string; }
+    leaf descr { type string; }
+    list port {
+      key name;
+      leaf name { type string; }
+      leaf mtu { type uint16; }
+    }
+    list rule {
+      key id;
+      ordered-by user;
+      leaf id { type uint8; }
+    }
+    leaf-list tags { type string; }
+  }
+}
+"#;
+
+    #[test]
+    fn yang_patch_edits_are_applied_in_order() {
+        use serde_json::json;
+        use yang2::data::{Data, DataPrinterFlags};
+
+        let ctx = Arc::new(context(&[("patch", PATCH_MODULE)]));
+        let base = || {
+            let base = json!({
+                "patch:c": { "name": "a", "port": [{ "name": "eth0", "mtu": 1500 }], "rule": [{ "id": 1 }] },
+            });
+            let path = Path::new("base.json");
+            parse_data_string(&ctx, path, &base.to_string(), &ParseOptions::default())
+        };
+        let print = |dtree: &DataTree| {
+            let data = dtree
+                .print_string(DataFormat::JSON, DataPrinterFlags::WITH_SIBLINGS)
+                .unwrap()
+                .unwrap_or_default();
+            serde_json::from_str::<serde_json::Value>(&data).unwrap()
+        };
+        let patch = |edits: serde_json::Value| {
+            YangDoc(json!({ "ietf-yang-patch:yang-patch": { "patch-id": "p", "edit": edits } }))
+        };
+
+        let mut dtree = base();
+        let mut errors = vec![];
+        let edits = json!([
+            { "edit-id": "1", "operation": "create", "target": "/patch:c/descr",
+              "value": { "patch:descr": "x" } },
+            { "edit-id": "2", "operation": "merge", "target": "/patch:c/port[name='eth0']",
+              "value": { "patch:port": [{ "name": "eth0", "mtu": 9000 }] } },
+            { "edit-id": "3", "operation": "replace", "target": "/patch:c/port[name='eth1']",
+              "value": { "patch:port": [{ "name": "eth1", "mtu": 1400 }] } },
+            { "edit-id": "4", "operation": "remove", "target": "/patch:c/tags" },
+            { "edit-id": "5", "operation": "merge", "target": "/patch:c/tags",
+              "value": { "patch:tags": ["p", "q"] } },
+            { "edit-id": "6", "operation": "insert", "target": "/patch:c/port[name='eth2']",
+              "value": { "patch:port": [{ "name": "eth2" }] } },
+        ]);
+        yang_patch_apply(&ctx, &mut dtree, &patch(edits), &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            print(&dtree),
+            json!({
+                "patch:c": {
+                    "name": "a",
+                    "descr": "x",
+                    "port": [
+                        { "name": "eth0", "mtu": 9000 },
+                        { "name": "eth1", "mtu": 1400 },
+                        { "name": "eth2" },
+                    ],
+                    "rule": [{ "id": 1 }],
+                    "tags": ["p", "q"],
+                },
+            })
+        );
+
+        let mut dtree = base();
+        let mut errors = vec![];
+        let edits = json!([
+            { "edit-id": "1", "operation": "create", "target": "/patch:c/name",
+              "value": { "patch:name": "b" } },
+            { "edit-id": "2", "operation": "delete", "target": "/patch:c/descr" },
+            { "edit-id": "3", "operation": "insert", "target": "/patch:c/rule[id='2']",
+              "value": { "patch:rule": [{ "id": 2 }] } },
+            { "edit-id": "4", "operation": "merge", "target": "/patch:c/descr" },
+        ]);
+        yang_patch_apply(&ctx, &mut dtree, &patch(edits), &mut errors);
+        let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                "/ietf-yang-patch:yang-patch/edit/0: 1: /patch:c/name: already exists",
+                "/ietf-yang-patch:yang-patch/edit/1: 2: /patch:c/descr: does not exist",
+                "/ietf-yang-patch:yang-patch/edit/2: 3: /patch:c/rule[id='2']: \
+                 insert into ordered-by user lists is not supported",
+                "/ietf-yang-patch:yang-patch/edit/3: 4: /patch:c/descr: value must hold the target node",
+            ]
+        );
+
+        let mut errors = vec![];
+        yang_patch_apply(&ctx, &mut dtree, &YangDoc(json!({})), &mut errors);
+        assert_eq!(
+            errors[0].message,
+            "not a YANG Patch document, /ietf-yang-patch:yang-patch/edit is missing"
+        );
+    }
+
+    #[test]
+    fn new_json_path_quotes_key_values() {
+        use serde_json::json;
+        use yang2::data::Data;
+
+        let ctx = Arc::new(context(&[("patch", PATCH_MODULE)]));
+        let base = json!({ "patch:c": { "name": "a" } });
+        let path = Path::new("base.json");
+        let mut dtree = parse_data_string(&ctx, path, &base.to_string(), &ParseOptions::default());
+        let value = json!({ "port": [{ "name": "it's", "mtu": 1 }], "tags": ["x"] });
+        new_json_path(&ctx, &mut dtree, "/patch:c", &value).unwrap();
+        assert!(dtree.find_path("/patch:c/port[name=\"it's\"]/mtu").is_ok());
+        assert!(dtree.find_path("/patch:c/tags[.='x']").is_ok());
+
+        let value = json!([{ "mtu": 1 }]);
+        let err = new_json_path(&ctx, &mut dtree, "/patch:c/port", &value).unwrap_err();
+        assert_eq!(err, "/patch:c/port: missing key name");
+    }
+
+    #[test]
+    fn json_strings_escape_control_and_non_ascii_characters() {
+        let value = serde_json::json!("a\nb\tc\u{1f600}\u{0}\u{1}\u{85}");
+        assert_eq!(
+            json_string(&value, false),
+            "\"a\\nb\\tc\u{1f600}\\u0000\\u0001\\u0085\""
+        );
+        assert_eq!(
+            json_string(&value, true),
+            r#""a\nb\tc\ud83d\ude00\u0000\u0001\u0085""#
+        );
+        let value = serde_json::json!({ "k\u{e9}y": ["\u{e9}"] });
+        assert_eq!(json_string(&value, false), "{\"k\u{e9}y\":[\"\u{e9}\"]}");
+        assert_eq!(json_string(&value, true), r#"{"k\u00e9y":["\u00e9"]}"#);
+    }
+
+    #[test]
+    fn invalid_string_characters_fail_or_are_stripped() {
+        let document = serde_json::json!({ "a": "ok\u{1}x", "b": ["fine", "\u{ffff}"], "n": 1 });
+
+        let mut value = document.clone();
+        let mut errors = vec![];
+        let mut report = Report::new(true);
+        check_string_chars(
+            &mut value,
+            "",
+            &InvalidChars::Error,
+            &mut errors,
+            &mut report,
+        );
+        assert_eq!(value, document);
+        let paths = errors
+            .iter()
+            .map(|error| error.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/a", "/b/1"]);
+        assert_eq!(
+            errors[0].message,
+            "\"ok\\u{1}x\": invalid characters for a YANG string"
+        );
+        assert!(report.items().is_empty());
+
+        let mut value = document.clone();
+        let mut errors = vec![];
+        check_string_chars(
+            &mut value,
+            "",
+            &InvalidChars::Strip,
+            &mut errors,
+            &mut report,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            value,
+            serde_json::json!({ "a": "okx", "b": ["fine", ""], "n": 1 })
+        );
+        let items = report.items();
+        assert_eq!(items.len(), 2);
+        assert_eq!((items[0].path.as_str(), items[0].action), ("/a", "strip"));
+        assert_eq!(items[0].before, Some(serde_json::json!("ok\u{1}x")));
+        assert_eq!(items[0].after, Some(serde_json::json!("okx")));
+        assert_eq!(items[1].path, "/b/1");
+    }
+
+    #[test]
+    fn missing_mandatory_nodes_follow_the_parse_options() {
+        let ctx = Arc::new(context(&[
+            ("choices", CHOICE_MODULE),
+            ("test", TEST_MODULE),
+        ]));
+        let missing = r#"{ "choices:c": { "plain": "x" } }"#;
+        let other_module = r#"{ "test:c": { "state": "up" } }"#;
+        let unknown =
+            r#"{ "choices:c": { "plain": "x", "required": "y", "interval": 1, "bogus": 1 } }"#;
+        let parses = |options: &ParseOptions, defaults: (bool, bool), data: &str| {
+            let (parser_flags, validation_flags) = options.flags(defaults.0, defaults.1);
+            DataTree::parse_string(&ctx, data, DataFormat::JSON, parser_flags, validation_flags)
+                .is_ok()
+        };
+        // the defaults of diff and of validation
+        let (diff, validate) = ((false, false), (true, true));
+        let options = |strict, validate, present_only| ParseOptions {
+            strict,
+            validate,
+            present_only,
+        };
+        let cases = [
+            (options(None, None, false), diff, missing, true),
+            (options(None, None, false), validate, missing, false),
+            (options(None, Some(true), false), diff, missing, false),
+            (options(None, Some(false), false), validate, missing, true),
+            (options(Some(true), None, false), diff, missing, true),
+            (options(Some(false), None, false), validate, missing, false),
+            (options(None, None, false), validate, other_module, false),
+            (options(None, None, true), validate, other_module, true),
+            (options(None, None, true), validate, missing, false),
+            (options(None, None, false), diff, unknown, true),
+            (options(Some(true), None, false), diff, unknown, false),
+        ];
+        for (i, (options, defaults, data, ok)) in cases.iter().enumerate() {
+            assert_eq!(parses(options, *defaults, data), *ok, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn json_parse_errors_hint_at_truncation_and_ndjson() {
+        let err = parse_json_documents("{\"a\": [1, 2", false).unwrap_err();
+        assert!(err.message.starts_with("EOF while parsing"), "{}", err);
+        assert!(
+            err.message.ends_with(", the input looks truncated"),
+            "{}",
+            err
+        );
+
+        let two = "{\"a\": 1}\n{\"b\": 2}\n";
+        let err = parse_json_documents(two, false).unwrap_err();
+        assert_eq!(
+            err.message,
+            "trailing data after the JSON document at line 2 column 1, near \"{\\\"b\\\": 2}\"; \
+             to convert concatenated documents one by one, use --ndjson"
+        );
+        assert_eq!(
+            parse_json_documents(two, true).unwrap(),
+            [serde_json::json!({ "a": 1 }), serde_json::json!({ "b": 2 })]
+        );
+    }
+
+    #[test]
+    fn template_wildcards_take_the_values_of_the_other_document() {
+        use serde_json::json;
+        let ctx = context(&[("patch", PATCH_MODULE)]);
+        let top_level = data_children(ctx.get_module_latest("patch").unwrap().data());
+        let mut template = json!({
+            "patch:c": {
+                "name": "__ANY__",
+                "tags": ["a", "__ANY__"],
+                // the concrete entry has to be matched first, or the
+                // wildcard entry would take eth0
+                "port": [{ "name": "__ANY__", "mtu": "__ANY__" }, { "name": "eth0", "mtu": 1500 }],
+            },
+        });
+        let other = json!({
+            "patch:c": {
+                "name": "r1",
+                "tags": ["b", "a"],
+                "port": [{ "name": "eth0", "mtu": 1500 }, { "name": "eth1", "mtu": 9000 }],
+            },
+        });
+        let mut instantiated = vec![];
+        instantiate_template(
+            &mut template,
+            &other,
+            &top_level,
+            "__ANY__",
+            "",
+            &mut instantiated,
+        );
+        assert_eq!(
+            template,
+            json!({
+                "patch:c": {
+                    "name": "r1",
+                    "tags": ["a", "b"],
+                    "port": [{ "name": "eth1", "mtu": 9000 }, { "name": "eth0", "mtu": 1500 }],
+                },
+            })
+        );
+        assert_eq!(
+            instantiated,
+            [
+                "/patch:c/name",
+                "/patch:c/port[name='eth1']",
+                "/patch:c/port[name='eth1']/mtu",
+                "/patch:c/tags[.='b']",
+            ]
+        );
+    }
+
+    #[test]
+    fn mapping_keeps_the_conversion_unchanged() {
+        let ctx = Arc::new(context(&[("ports", PORTS_MODULE)]));
+        let module = ctx.get_module_latest("ports").unwrap();
+        let records = option_mapping(module.data(), &GenerateOptions::default());
+        let dir = tempfile::tempdir().unwrap();
+        let mapping = dir.path().join("mapping.json");
+        std::fs::write(&mapping, serde_json::to_string_pretty(&records).unwrap()).unwrap();
+        let key_orders = read_mapping(&ctx, &mapping);
+        assert_eq!(
+            key_orders,
+            BTreeMap::from([(
+                "/ports:port".to_string(),
+                strings(&["name", "slot", "vlan"])
+            )])
+        );
+
+        let yang = serde_json::json!({
+            "ports:port": [
+                { "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 },
+                { "name": "eth0", "slot": 2, "vlan": 100 },
+            ],
+        });
+        let path = Path::new("ports.json");
+        let with_mapping = ConvertOptions {
+            key_orders,
+            ..Default::default()
+        };
+        let convert = |options: &ConvertOptions| {
+            let mut report = Report::default();
+            let doc = YangDoc(yang.clone());
+            let nix = yang_to_nix(&ctx, path, doc, options, &mut report).unwrap();
+            let back = nix_to_yang(&ctx, path, nix.clone(), options, &mut report).unwrap();
+            (nix.into_inner(), back.into_inner())
+        };
+        let without = convert(&ConvertOptions::default());
+        assert_eq!(convert(&with_mapping), without);
+    }
+
+    #[test]
+    fn failed_writes_leave_the_previous_file_alone() {
+        use std::io::Write;
+
+        // a writer that fails once it has written `remaining` bytes
+        struct FailAfter<'w> {
+            inner: &'w mut dyn Write,
+            remaining: usize,
+        }
+        impl Write for FailAfter<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.remaining == 0 {
+                    return Err(std::io::Error::other("injected failure"));
+                }
+                let n = self.inner.write(&buf[..buf.len().min(self.remaining)])?;
+                self.remaining -= n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.inner.flush()
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.json");
+        write_atomic(&path, "old contents").unwrap();
+        let err = write_atomic_with(&path, |inner| {
+            FailAfter {
+                inner,
+                remaining: 4,
+            }
+            .write_all(b"new contents")
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{}: injected failure", path.display())
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old contents");
+        // the temporary file is removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        write_atomic(&path, "new contents").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new contents");
+    }
+
+    #[test]
+    fn writing_over_a_directory_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = write_atomic(dir.path(), "contents").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{}: is a directory", dir.path().display())
+        );
+        assert!(dir.path().is_dir());
+    }
+
+    #[test]
+    fn provenance_comments_without_timestamp_are_stable() {
+        let ctx = context(&[("test", TEST_MODULE)]);
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("device.json");
+        std::fs::write(&input, "{\"test:c\":{\"state\":\"down\"}}\n").unwrap();
+        let data = serde_json::json!({ "c": { "state": "down", "states": ["up", "down"] } });
+        let (modules, fingerprint) = schema_fingerprint(&ctx);
+        let expected = format!(
+            "# Generated from {}\n\
+             # sha256: 9f3c22760764072f7c582fb82254cbfbaa3517fb843a22e1b2a501e08b28da73\n\
+             # by {} {}\n\
+             # schema: {} ({} modules)\n\
+             #   c: 3 values\n",
+            input.display(),
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            fingerprint,
+            modules.len(),
+        );
+        assert_eq!(provenance_comment(&ctx, &input, &data, false), expected);
+        assert_eq!(provenance_comment(&ctx, &input, &data, false), expected);
+        assert!(provenance_comment(&ctx, &input, &data, true).contains("\n# at "));
+    }
+
+    #[test]
+    fn documents_over_the_size_limits_fail() {
+        use serde_json::json;
+
+        // the object, the array and its two numbers, and the inner object
+        // and its boolean
+        let data = json!({ "a": [1, 2], "b": { "c": true } });
+        assert!(!exceeds_nodes(&data, 6));
+        assert!(exceeds_nodes(&data, 5));
+        assert!(!exceeds_nodes(&json!(null), 1));
+        assert!(exceeds_nodes(&json!(null), 0));
+
+        let ctx = context(&[("ports", PORTS_MODULE), ("patch", PATCH_MODULE)]);
+        let top_level = data_children(
+            ["ports", "patch"]
+                .into_iter()
+                .flat_map(|name| ctx.get_module_latest(name).unwrap().data()),
+        );
+        let cases = [
+            (
+                json!({ "ports:port": [
+                    { "name": "eth0", "slot": 1, "vlan": 100 },
+                    { "name": "eth0", "slot": 1, "vlan": 200 },
+                    { "name": "eth1", "slot": 1, "vlan": 100 },
+                ] }),
+                false,
+                "/ports:port",
+                3,
+            ),
+            (
+                json!({ "ports:port": {
+                    "eth0": { "1": { "100": {}, "200": {} } },
+                    "eth1": { "1": { "100": {} } },
+                } }),
+                true,
+                "/ports:port",
+                3,
+            ),
+            (
+                json!({ "patch:c": { "port": [{ "name": "a" }, { "name": "b" }] } }),
+                false,
+                "/patch:c/port",
+                2,
+            ),
+            (
+                json!({ "patch:c": { "port": { "a": {}, "b": {} } } }),
+                true,
+                "/patch:c/port",
+                2,
+            ),
+        ];
+        for (data, nix, path, count) in cases {
+            let mut errors = vec![];
+            assert!(!exceeds_list_size(
+                &data,
+                "",
+                &top_level,
+                nix,
+                count,
+                &mut errors
+            ));
+            assert!(errors.is_empty(), "{:?}", errors);
+            assert!(exceeds_list_size(
+                &data,
+                "",
+                &top_level,
+                nix,
+                count - 1,
+                &mut errors
+            ));
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].path, path);
+            assert_eq!(
+                errors[0].message,
+                format!(
+                    "list has {} entries, more than --max-entries-per-list {}",
+                    count,
+                    count - 1
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn descriptions_are_truncated_keeping_or_joining_their_lines() {
+        let ctx = context(&[(
+            "desc",
+            r#"
+module desc {
+  yang-version 1.1;
+  namespace "urn:desc";
+  prefix d;
+
+  leaf motd {
+    type string;
+    description
+      "First line of the
+       description.
+
+       Second paragraph.";
+  }
+}
+"#,
+        )]);
+        let node = ctx.find_path("/desc:motd").unwrap();
+        let lines = |max_description_length, flatten_descriptions| {
+            let options = GenerateOptions {
+                max_description_length,
+                flatten_descriptions,
+                ..Default::default()
+            };
+            description_lines(&node, &options)
+        };
+        assert_eq!(
+            lines(None, false),
+            ["First line of the", "description.", "", "Second paragraph."]
+        );
+        assert_eq!(
+            lines(Some(20), false),
+            [
+                "First line of the",
+                "des…",
+                "(truncated, see the description of /desc:motd)"
+            ]
+        );
+        assert_eq!(
+            lines(None, true),
+            ["First line of the description. Second paragraph."]
+        );
+        assert_eq!(lines(Some(20), true), ["First line of the de..."]);
+    }
+
+    #[test]
+    fn data_paths_are_split_into_segments() {
+        let segment = |module: Option<&str>, name: &str, predicates: &[(&str, &str)]| PathSegment {
+            module: module.map(String::from),
+            name: name.to_string(),
+            predicates: predicates
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        let cases = [
+            (
+                "/m:a/b[name='x/y]z']/c",
+                vec![
+                    segment(Some("m"), "a", &[]),
+                    segment(None, "b", &[("name", "x/y]z")]),
+                    segment(None, "c", &[]),
+                ],
+            ),
+            (
+                "/m:port[k1=\"a'b\"][k2 = '2']",
+                vec![segment(Some("m"), "port", &[("k1", "a'b"), ("k2", "2")])],
+            ),
+            (
+                "  a/b  ",
+                vec![segment(None, "a", &[]), segment(None, "b", &[])],
+            ),
+            ("/", vec![]),
+        ];
+        for (path, segments) in cases {
+            assert_eq!(parse_data_path(path), segments, "{}", path);
+        }
+    }
+
+    #[test]
+    fn path_prefix_segments_match_data_path_segments() {
+        let cases = [
+            // a list without predicates stands for all of its entries
+            ("/m:port", "/m:port[name='eth0']", true),
+            ("/m:port[name='eth0']", "/m:port", false),
+            ("/m:port[name='eth0']", "/m:port[name='eth1']", false),
+            ("/m:port[name='a/b]']", "/port[name=\"a/b]\"]", true),
+            // qualifiers only need to agree if both have one
+            ("/port", "/m:port", true),
+            ("/m:port", "/port", true),
+            ("/x:port", "/m:port", false),
+            ("/m:port", "/m:ports", false),
+        ];
+        for (prefix, path, matches) in cases {
+            let prefix = &parse_data_path(prefix)[0];
+            let path = &parse_data_path(path)[0];
+            assert_eq!(prefix.matches(path), matches, "{:?} {:?}", prefix, path);
+        }
+    }
+
+    #[test]
+    fn visible_paths_show_their_subtrees_and_ancestors() {
+        let visible = VisiblePaths(vec![
+            parse_data_path("/m:c/port[name='eth0']"),
+            parse_data_path("/m:sys/name"),
+        ]);
+        let cases = [
+            ("/m:c/port[name='eth0']", Visibility::Visible),
+            ("/m:c/port[name='eth0']/mtu", Visibility::Visible),
+            ("/c/port[name='eth0']/mtu", Visibility::Visible),
+            ("/m:sys/name", Visibility::Visible),
+            ("/m:c", Visibility::Ancestor),
+            ("/m:sys", Visibility::Ancestor),
+            ("/m:c/port[name='eth1']", Visibility::Hidden),
+            ("/m:c/port[name='eth1']/mtu", Visibility::Hidden),
+            ("/m:sys/location", Visibility::Hidden),
+            ("/other:top", Visibility::Hidden),
+        ];
+        for (path, visibility) in cases {
+            assert_eq!(visible.visibility(path), visibility, "{}", path);
+        }
+    }
+
+    #[test]
+    fn members_nested_by_module_round_trip() {
+        use serde_json::json;
+        let ctx = Arc::new(context(&[("test", TEST_MODULE), ("ports", PORTS_MODULE)]));
+        let options = ConvertOptions {
+            namespace_by_module: true,
+            ..Default::default()
+        };
+        let path = Path::new("nested.json");
+        let mut report = Report::default();
+        let yang = json!({
+            "test:c": { "state": "down" },
+            "ports:port": [{ "name": "eth0", "slot": 1, "vlan": 100, "mtu": 1500 }],
+        });
+        let nix = yang_to_nix(&ctx, path, YangDoc(yang.clone()), &options, &mut report).unwrap();
+        assert_eq!(
+            nix.0,
+            json!({
+                "test": { "c": { "state": "down" } },
+                "ports": { "port": { "eth0": { "1": { "100": { "mtu": 1500 } } } } },
+            })
+        );
+        let back = nix_to_yang(&ctx, path, nix, &options, &mut report).unwrap();
+        assert_eq!(back.into_inner(), yang);
+
+        let nix = json!({
+            "test": { "c": { "state": "down" } },
+            "test:c": { "state": "up" },
+        });
+        let errors = nix_to_yang(&ctx, path, NixDoc(nix), &options, &mut report).unwrap_err();
+        let errors = errors
+            .iter()
+            .map(|error| (error.path.as_str(), error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [("/test/c", "also given outside of the module")]);
+    }
 }